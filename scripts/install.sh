@@ -3,7 +3,7 @@ APP_PATH="/opt/ClusterNoodle"
 BIN_PATH="/opt/ClusterNoodle/ClusterNoodle"
 BIN_SYMLINK_PATH="/usr/local/bin/ClusterNoodle"
 CONFIG_PATH="$HOME/.config/ClusterNoodle"
-CONFIG_FILE_PATH="$CONFIG_PATH/conf.cluster_noodle"
+CONFIG_FILE_PATH="$CONFIG_PATH/cluster.yml"
 EXISTING_ENV_FILE_PATH="$CONFIG_PATH/app.env"
 NEW_ENV_FILE_PATH="$APP_PATH/app.env"
 SCRIPTS_PATH="$APP_PATH/scripts"
@@ -32,7 +32,7 @@ echo "Setting up config folder in $CONFIG_PATH"
 sudo mkdir -p $CONFIG_PATH
 
 if ! [ -z "$CONFIG_FILE_PATH" ] && ! [ -e "$CONFIG_FILE_PATH" ]; then
-    sudo touch "$CONFIG_FILE_PATH"
+    printf 'version: 1\nnodes: []\nservices: {}\n' | sudo tee "$CONFIG_FILE_PATH" > /dev/null
 fi
 
 
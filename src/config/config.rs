@@ -1,26 +1,139 @@
 #![allow(dead_code)]
+use crate::docker::bundle::Bundle;
 use crate::fs::path_exists;
 use crate::services::services::Services;
 use crate::utils::envVariables::EnvVariables;
+use crate::utils::runner::{CommandRunner, default_runner};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::sync::Arc;
 
+// Version du format du fichier de définition du cluster supportée par cette version de l'outil.
+pub const CLUSTER_FILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Manager,
+    #[default]
+    Worker,
+}
+
+// Une clé inconnue (faute de frappe) est refusée plutôt qu'ignorée en silence.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NodeConfig {
     pub ip: String,
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    // Mot de passe sudo s'il diffère du mot de passe SSH.
+    #[serde(default)]
+    pub sudo_password: Option<String>,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub role: NodeRole,
+    // Clé privée propre à la node, à la place de ~/.ssh/cluster_noodle.
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    // Utilise les clés chargées dans l'agent SSH (SSH_AUTH_SOCK).
+    #[serde(default)]
+    pub ssh_agent: bool,
+    // Empreinte attendue de la clé d'hôte SSH de la node (format `SHA256:...`).
+    #[serde(default)]
+    pub host_key_fingerprint: Option<String>,
+}
+
+impl NodeConfig {
+    pub fn new(ip: &str, username: &str, role: NodeRole) -> NodeConfig {
+        NodeConfig {
+            ip: ip.to_string(),
+            username: username.to_string(),
+            password: String::new(),
+            sudo_password: None,
+            port: default_ssh_port(),
+            role,
+            host_key_fingerprint: None,
+            identity_file: None,
+            ssh_agent: false,
+        }
+    }
+
+    // Mot de passe donné à sudo : sudo_password s'il est déclaré, sinon le mot de passe SSH.
+    pub fn effective_sudo_password(&self) -> Option<String> {
+        self.sudo_password
+            .clone()
+            .or_else(|| Some(self.password.clone()))
+            .filter(|password| !password.is_empty())
+    }
+}
+
+pub fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_parallelism() -> usize {
+    5
+}
+
+fn default_compose_file_path() -> String {
+    EnvVariables {}.get_docker_file_path()
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ClusterConfig {
+    pub version: u32,
+    #[serde(default)]
     pub ip_adress: Option<String>,
+    #[serde(skip)]
     pub nodes_number: u16,
+    #[serde(rename = "nodes", default)]
     pub nodes_configs: Vec<NodeConfig>,
-    pub cluster_docker_command: String,
+    #[serde(skip)]
+    pub manager_join_command: String,
+    #[serde(skip)]
+    pub worker_join_command: String,
+    #[serde(default)]
     pub project_folder_path: Option<String>,
+    #[serde(default)]
     pub project_entry_file_path: Option<String>,
+    #[serde(default)]
     pub ssl_certificate_path_key: Option<String>,
+    #[serde(default)]
     pub ssl_certificate_path_crt: Option<String>,
+    #[serde(default)]
     pub services: Services,
-    pub docker_images: Vec<String>
+    // Version exacte du moteur docker attendue sur toutes les machines du cluster.
+    #[serde(default)]
+    pub docker_version: Option<String>,
+    // Les nodes n'ont pas accès à internet : le manager tire les images et les leur transfère
+    // par SSH (docker save / docker load).
+    #[serde(default)]
+    pub air_gapped: bool,
+    // Le dossier du projet et la configuration du serveur sont copiés sur chaque node au lieu
+    // d'être intégrés à une image (start --sync-project).
+    #[serde(default)]
+    pub sync_project: bool,
+    // Nombre maximum de nodes traitées en même temps.
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    #[serde(skip)]
+    pub docker_images: Vec<String>,
+    // Fichier docker-compose généré puis déployé.
+    #[serde(skip, default = "default_compose_file_path")]
+    pub compose_file_path: String,
+    // Toutes les commandes locales et distantes passent par ce runner.
+    #[serde(skip, default = "default_runner")]
+    pub runner: Arc<dyn CommandRunner>,
+    // Bundle hors ligne (start --bundle) : images et paquets docker sont pris dans le bundle.
+    #[serde(skip)]
+    pub bundle: Option<Bundle>,
+    // Avec --dry-run, aucun fichier n'est écrit et les commandes sont seulement affichées.
+    #[serde(skip)]
+    pub dry_run: bool,
 }
 
 pub fn init_app_config_folder() {
@@ -29,47 +142,231 @@ pub fn init_app_config_folder() {
     dotenvy::from_path(&env.get_env_file_path()).expect(&format!("Can't load env variables because no file found : {}", &env.get_env_file_path()));
 }
 
-pub fn check_conf_file_exists() {
-    let env = EnvVariables {};
-    let conf_file_path = env.get_conf_file_path();
-
-    match path_exists(&conf_file_path) {
+pub fn check_conf_file_exists(conf_file_path: &str) {
+    match path_exists(conf_file_path) {
         Ok(_v) => (),
         Err(_e) => {
             panic!(
-                "No conf file found. You need to declare servers in the conf file (~/.config/conf.cluster_noodle)"
+                "No conf file found. You need to declare your cluster in the conf file ({})",
+                conf_file_path
             );
         }
     }
 }
 
-pub fn build_cluster_nodes_objects(file_path: &str) -> Vec<NodeConfig> {
+pub fn load_cluster_config(file_path: &str) -> Result<ClusterConfig, String> {
+    let contents = fs::read_to_string(file_path)
+        .map_err(|e| format!("Failed to read config file ({}) : {}", file_path, e))?;
+
+    let config: ClusterConfig = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Invalid config file ({}) : {}", file_path, e))?;
+
+    if config.version != CLUSTER_FILE_VERSION {
+        return Err(format!(
+            "Unsupported config file version {} in {} (expected version {})",
+            config.version, file_path, CLUSTER_FILE_VERSION
+        ));
+    }
+
+    for node_config in &config.nodes_configs {
+        if node_config.ip.trim().is_empty() || node_config.username.trim().is_empty() {
+            return Err(String::from(
+                "For each node declared in the conf file you need to provide at least ip and username",
+            ));
+        }
+    }
+
+    Ok(config)
+}
+
+// Ajoute une node à la liste `nodes` du fichier de définition. Le fichier est modifié ligne à
+// ligne pour conserver les commentaires et la mise en forme de l'utilisateur.
+pub fn add_node_to_cluster_file(file_path: &str, node_config: &NodeConfig) -> Result<(), String> {
     let contents = fs::read_to_string(file_path)
-        .expect("Failed to read config file (conf.cluster_noodle). \n");
+        .map_err(|e| format!("Failed to read config file ({}) : {}", file_path, e))?;
+    let contents = add_node_to_cluster_yaml(&contents, node_config)
+        .map_err(|e| format!("{} ({})", e, file_path))?;
+    fs::write(file_path, contents).map_err(|e| format!("Failed to write config file ({}) : {}", file_path, e))
+}
 
+fn add_node_to_cluster_yaml(contents: &str, node_config: &NodeConfig) -> Result<String, String> {
+    let document: serde_yaml::Value =
+        serde_yaml::from_str(contents).map_err(|e| format!("Invalid config file : {}", e))?;
+    let entry = node_entry(node_config)?;
     let lines: Vec<&str> = contents.lines().collect();
-    let mut nodes_configs: Vec<NodeConfig> = vec![];
-
-    for line in lines {
-        let data: Vec<&str> = line.split(",").collect::<Vec<&str>>();
-        if data.len() > 2 {
-            let node_config = NodeConfig {
-                ip: data[0].to_string(),
-                username: data[1].to_string(),
-                password: data[2].to_string(),
-            };
-            nodes_configs.push(node_config);
-        } else if data.len() > 1 {
-            let node_config = NodeConfig {
-                ip: data[0].to_string(),
-                username: data[1].to_string(),
-                password: String::from(""),
-            };
-            nodes_configs.push(node_config);
-        } else if data.len() == 1 {
-            panic!("For each server declared in the conf file you need to provide at least ip,username");
+    let nodes_line = lines.iter().position(|line| {
+        line.strip_prefix("nodes:")
+            .is_some_and(|rest| rest.trim().is_empty() || rest.trim_start().starts_with('#'))
+    });
+
+    let updated = match nodes_line {
+        Some(start) => {
+            // Le bloc se termine à la première clé de premier niveau qui suit `nodes:`.
+            let mut end = start + 1;
+            let mut indent = None;
+            for (index, line) in lines.iter().enumerate().skip(start + 1) {
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if !line.starts_with([' ', '\t', '-']) {
+                    break;
+                }
+                end = index + 1;
+                if indent.is_none() && line.trim_start().starts_with('-') {
+                    indent = Some(&line[..line.len() - line.trim_start().len()]);
+                }
+            }
+
+            let mut updated: Vec<String> = lines[..end].iter().map(|line| line.to_string()).collect();
+            updated.extend(indent_lines(&entry, indent.unwrap_or("  ")));
+            updated.extend(lines[end..].iter().map(|line| line.to_string()));
+            updated.join("\n") + "\n"
         }
+        None if document.get("nodes").is_none() => {
+            let mut updated = contents.to_string();
+            if !updated.is_empty() && !updated.ends_with('\n') {
+                updated.push('\n');
+            }
+            updated.push_str("nodes:\n");
+            for line in indent_lines(&entry, "  ") {
+                updated.push_str(&line);
+                updated.push('\n');
+            }
+            updated
+        }
+        None => return Err(String::from("The nodes of the config file must be a block list to add a node")),
+    };
+
+    // Le fichier modifié doit rester un fichier de définition valide contenant la node.
+    let config: ClusterConfig =
+        serde_yaml::from_str(&updated).map_err(|e| format!("Failed to add {} to the config file : {}", node_config.ip, e))?;
+    if !config.nodes_configs.iter().any(|declared| declared.ip == node_config.ip) {
+        return Err(format!("Failed to add {} to the config file", node_config.ip));
+    }
+    Ok(updated)
+}
+
+// Entrée YAML d'une node, seuls les champs qui diffèrent des valeurs par défaut sont écrits.
+fn node_entry(node_config: &NodeConfig) -> Result<String, String> {
+    let mut entry = serde_yaml::Mapping::new();
+    entry.insert("ip".into(), node_config.ip.clone().into());
+    entry.insert("username".into(), node_config.username.clone().into());
+    if !node_config.password.is_empty() {
+        entry.insert("password".into(), node_config.password.clone().into());
+    }
+    if let Some(sudo_password) = &node_config.sudo_password {
+        entry.insert("sudo_password".into(), sudo_password.clone().into());
+    }
+    if node_config.port != default_ssh_port() {
+        entry.insert("port".into(), node_config.port.into());
+    }
+    if node_config.role == NodeRole::Manager {
+        entry.insert("role".into(), "manager".into());
+    }
+    if let Some(identity_file) = &node_config.identity_file {
+        entry.insert("identity_file".into(), identity_file.clone().into());
+    }
+    if node_config.ssh_agent {
+        entry.insert("ssh_agent".into(), true.into());
+    }
+    serde_yaml::to_string(&vec![entry]).map_err(|e| format!("Failed to serialize node {} : {}", node_config.ip, e))
+}
+
+fn indent_lines(text: &str, indent: &str) -> Vec<String> {
+    text.lines().map(|line| format!("{}{}", indent, line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_node() -> NodeConfig {
+        let mut node_config = NodeConfig::new("10.0.0.4", "deploy", NodeRole::Worker);
+        node_config.password = String::from("it's secret");
+        node_config.port = 2222;
+        node_config
+    }
+
+    // Écrit `contents` dans un fichier de définition temporaire et le charge.
+    fn load(name: &str, contents: &str) -> Result<ClusterConfig, String> {
+        let path = std::env::temp_dir().join(format!("cluster_noodle_{}_{}.yml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let config = load_cluster_config(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        config
     }
 
-    return nodes_configs;
+    #[test]
+    fn load_cluster_config_reads_the_file() {
+        let config = load(
+            "valid",
+            "version: 1\nnodes:\n  - ip: 10.0.0.2\n    username: deploy\n    role: manager\nservices:\n  server: nginx\n  traefik: true\n",
+        )
+        .unwrap();
+        assert_eq!(config.nodes_configs.len(), 1);
+        assert_eq!(config.nodes_configs[0].role, NodeRole::Manager);
+        assert_eq!(config.nodes_configs[0].port, 22);
+        assert!(config.services.traefik);
+        assert_eq!(config.parallelism, 5);
+    }
+
+    #[test]
+    fn load_cluster_config_rejects_invalid_files() {
+        let cases = [
+            ("version", "version: 2\n", "Unsupported config file version 2"),
+            ("no_version", "nodes: []\n", "missing field `version`"),
+            ("yaml", "version: 1\nnodes: [\n", "Invalid config file"),
+            ("node_key", "version: 1\nnodes:\n  - ip: 10.0.0.2\n    username: deploy\n    host_key_fingerprnt: SHA256:abc\n", "unknown field `host_key_fingerprnt`"),
+            ("services_key", "version: 1\nservices:\n  traefk: true\n", "unknown field `traefk`"),
+            ("cluster_key", "version: 1\nair_gaped: true\n", "unknown field `air_gaped`"),
+            ("empty_ip", "version: 1\nnodes:\n  - ip: ''\n    username: deploy\n", "at least ip and username"),
+        ];
+
+        for (name, contents, expected) in cases {
+            let error = load(name, contents).err().unwrap();
+            assert!(error.contains(expected), "{} : {}", name, error);
+        }
+    }
+
+    #[test]
+    fn load_cluster_config_without_file() {
+        let path = std::env::temp_dir().join(format!("cluster_noodle_missing_{}.yml", std::process::id()));
+        let error = load_cluster_config(&path.to_string_lossy()).err().unwrap();
+        assert!(error.starts_with("Failed to read config file"), "{}", error);
+    }
+
+    #[test]
+    fn add_node_keeps_comments_and_indentation() {
+        let contents = "# Cluster de production\nversion: 1\nnodes:\n    # Première node\n    - ip: 10.0.0.2\n      username: deploy\n\n# Services\nservices:\n  server: nginx\n";
+        let updated = add_node_to_cluster_yaml(contents, &new_node()).unwrap();
+        assert_eq!(
+            updated,
+            "# Cluster de production\nversion: 1\nnodes:\n    # Première node\n    - ip: 10.0.0.2\n      username: deploy\n    - ip: 10.0.0.4\n      username: deploy\n      password: it's secret\n      port: 2222\n\n# Services\nservices:\n  server: nginx\n"
+        );
+
+        let config: ClusterConfig = serde_yaml::from_str(&updated).unwrap();
+        let added = &config.nodes_configs[1];
+        assert_eq!(added.password, "it's secret");
+        assert_eq!(added.port, 2222);
+        assert_eq!(added.role, NodeRole::Worker);
+    }
+
+    #[test]
+    fn add_node_to_unindented_list() {
+        let contents = "version: 1\nnodes:\n- ip: 10.0.0.2\n  username: deploy\n";
+        let mut node_config = NodeConfig::new("10.0.0.4", "admin", NodeRole::Manager);
+        node_config.ssh_agent = true;
+        assert_eq!(
+            add_node_to_cluster_yaml(contents, &node_config).unwrap(),
+            "version: 1\nnodes:\n- ip: 10.0.0.2\n  username: deploy\n- ip: 10.0.0.4\n  username: admin\n  role: manager\n  ssh_agent: true\n"
+        );
+    }
+
+    #[test]
+    fn add_node_without_nodes() {
+        let updated = add_node_to_cluster_yaml("version: 1\nservices:\n  server: nginx", &new_node()).unwrap();
+        assert!(updated.starts_with("version: 1\nservices:\n  server: nginx\nnodes:\n  - ip: 10.0.0.4\n"), "{}", updated);
+
+        assert!(add_node_to_cluster_yaml("version: 1\nnodes: []\n", &new_node()).is_err());
+    }
 }
@@ -0,0 +1,313 @@
+#![allow(dead_code)]
+use crate::config::config::{ClusterConfig, NodeConfig, NodeRole, default_ssh_port};
+use crate::docker::cluster::SwarmNode;
+use crate::services::services::Services;
+use crate::utils::envVariables::EnvVariables;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// Node ayant rejoint le cluster.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NodeState {
+    pub ip: String,
+    pub username: String,
+    pub role: NodeRole,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub swarm_node_id: Option<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+// Ce que ClusterNoodle a déployé, conservé entre deux commandes dans state.json.
+#[derive(Serialize, Deserialize)]
+pub struct ClusterState {
+    pub advertise_addr: String,
+    pub stack_name: String,
+    #[serde(default)]
+    pub compose_hash: Option<String>,
+    #[serde(default)]
+    pub services: Services,
+    #[serde(default)]
+    pub docker_images: Vec<String>,
+    #[serde(default)]
+    pub nodes: Vec<NodeState>,
+    // Commandes de jointure, le fichier n'est lisible que par son propriétaire.
+    #[serde(default)]
+    pub manager_join_command: String,
+    #[serde(default)]
+    pub worker_join_command: String,
+    #[serde(default)]
+    pub checkpoints: Checkpoints,
+    // Ce que la vérification préalable a constaté sur chaque node, par adresse.
+    #[serde(default)]
+    pub node_facts: BTreeMap<String, NodeFacts>,
+    // Le projet est copié sur les nodes (start --sync-project), apply le recopie.
+    #[serde(default)]
+    pub sync_project: bool,
+    // Avec --dry-run, l'état n'est jamais écrit.
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+// État d'une node constaté avant l'installation de docker.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct NodeFacts {
+    pub os: String,
+    #[serde(default)]
+    pub docker_version: Option<String>,
+    #[serde(default)]
+    pub docker_running: bool,
+    // L'utilisateur peut utiliser docker sans sudo.
+    #[serde(default)]
+    pub docker_group: bool,
+    // Ce que la vérification a dû installer (outils, docker).
+    #[serde(default)]
+    pub installed: Vec<String>,
+}
+
+impl NodeFacts {
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "{}, docker {}",
+            self.os,
+            self.docker_version.as_deref().unwrap_or("(unknown version)")
+        );
+        if !self.installed.is_empty() {
+            description.push_str(&format!(", installed : {}", self.installed.join(", ")));
+        }
+        description
+    }
+}
+
+// Avancement du démarrage du cluster : étapes terminées et, pour les étapes exécutées
+// sur chaque node, les nodes qui les ont déjà terminées.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Checkpoints {
+    #[serde(default)]
+    pub completed_stages: Vec<String>,
+    #[serde(default)]
+    pub completed_nodes: BTreeMap<String, Vec<String>>,
+}
+
+impl ClusterState {
+    pub fn from_config(config: &ClusterConfig, stack_name: &str) -> ClusterState {
+        ClusterState {
+            advertise_addr: config.ip_adress.clone().unwrap_or_default(),
+            stack_name: stack_name.to_string(),
+            compose_hash: hash_file(&config.compose_file_path),
+            services: config.services.clone(),
+            docker_images: config.docker_images.clone(),
+            nodes: vec![],
+            manager_join_command: config.manager_join_command.clone(),
+            worker_join_command: config.worker_join_command.clone(),
+            checkpoints: Checkpoints::default(),
+            node_facts: BTreeMap::new(),
+            sync_project: config.sync_project,
+            dry_run: config.dry_run,
+        }
+    }
+
+    pub fn record_joined_node(&mut self, node_config: &NodeConfig) {
+        if self.nodes.iter().any(|node_state| node_state.ip == node_config.ip) {
+            return;
+        }
+
+        self.nodes.push(NodeState {
+            ip: node_config.ip.clone(),
+            username: node_config.username.clone(),
+            role: node_config.role,
+            port: node_config.port,
+            swarm_node_id: None,
+            hostname: None,
+        });
+    }
+
+    // Oublie une node retirée du cluster, y compris son avancement dans les étapes.
+    pub fn forget_node(&mut self, ip: &str) {
+        self.nodes.retain(|node_state| node_state.ip != ip);
+        self.node_facts.remove(ip);
+        for ips in self.checkpoints.completed_nodes.values_mut() {
+            ips.retain(|completed| completed != ip);
+        }
+    }
+
+    pub fn is_stage_completed(&self, stage: &str) -> bool {
+        self.checkpoints.completed_stages.iter().any(|completed| completed == stage)
+    }
+
+    pub fn complete_stage(&mut self, stage: &str) {
+        if !self.is_stage_completed(stage) {
+            self.checkpoints.completed_stages.push(stage.to_string());
+        }
+    }
+
+    pub fn is_node_completed(&self, stage: &str, ip: &str) -> bool {
+        self.checkpoints
+            .completed_nodes
+            .get(stage)
+            .is_some_and(|ips| ips.iter().any(|completed| completed == ip))
+    }
+
+    pub fn complete_node(&mut self, stage: &str, ip: &str) {
+        if !self.is_node_completed(stage, ip) {
+            self.checkpoints
+                .completed_nodes
+                .entry(stage.to_string())
+                .or_default()
+                .push(ip.to_string());
+        }
+    }
+
+    // Associe chaque node enregistrée à son identifiant swarm grâce à son adresse.
+    pub fn set_swarm_nodes(&mut self, swarm_nodes: &[SwarmNode]) {
+        for node_state in &mut self.nodes {
+            if let Some(swarm_node) = swarm_nodes.iter().find(|swarm_node| swarm_node.addr == node_state.ip) {
+                node_state.swarm_node_id = Some(swarm_node.id.clone());
+                node_state.hostname = Some(swarm_node.hostname.clone());
+            }
+        }
+    }
+
+    // Nom d'hôte swarm de chaque node enregistrée, indexé par son adresse.
+    pub fn hostnames(&self) -> BTreeMap<String, String> {
+        self.nodes
+            .iter()
+            .filter_map(|node_state| Some((node_state.ip.clone(), node_state.hostname.clone()?)))
+            .collect()
+    }
+
+    pub fn load() -> Result<Option<ClusterState>, String> {
+        let env = EnvVariables {};
+        let state_file_path = env.get_state_file_path();
+
+        if !Path::new(&state_file_path).exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&state_file_path)
+            .map_err(|e| format!("Failed to read state file ({}) : {}", state_file_path, e))?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| format!("Invalid state file ({}) : {}", state_file_path, e))
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let env = EnvVariables {};
+        let state_file_path = env.get_state_file_path();
+
+        if self.dry_run {
+            println!("[dry-run] state file {} not written", state_file_path);
+            return Ok(());
+        }
+
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize cluster state : {}", e))?;
+        // Le fichier contient les jetons de jointure.
+        write_private_file(Path::new(&state_file_path), &contents)
+            .map_err(|e| format!("Failed to write state file ({}) : {}", state_file_path, e))
+    }
+
+    pub fn remove() -> Result<(), String> {
+        let env = EnvVariables {};
+        let state_file_path = env.get_state_file_path();
+
+        match fs::remove_file(&state_file_path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to remove state file ({}) : {}", state_file_path, e)),
+        }
+    }
+
+    // Nodes enregistrées, avec les identifiants du fichier de définition quand la node y
+    // est encore déclarée.
+    pub fn node_configs(&self, config: &ClusterConfig) -> Vec<NodeConfig> {
+        self.nodes
+            .iter()
+            .map(|node_state| {
+                config
+                    .nodes_configs
+                    .iter()
+                    .find(|node_config| node_config.ip == node_state.ip)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        let mut node_config =
+                            NodeConfig::new(&node_state.ip, &node_state.username, node_state.role);
+                        node_config.port = node_state.port;
+                        node_config
+                    })
+            })
+            .collect()
+    }
+}
+
+pub fn hash_file(file_path: &str) -> Option<String> {
+    let contents = fs::read(file_path).ok()?;
+    Some(format!("{:x}", Sha256::digest(contents)))
+}
+
+// Écrit un fichier lisible par son seul propriétaire dès sa création. Le contenu est écrit
+// dans un fichier temporaire renommé ensuite, une interruption ne laisse donc jamais un
+// fichier tronqué.
+fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = Path::new(&temp_path);
+
+    // Un fichier temporaire laissé par une interruption garderait ses permissions.
+    match fs::remove_file(temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_folder(name: &str) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!("ClusterNoodle-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn private_file_is_never_readable_by_others() {
+        let folder = temp_folder("state");
+        let path = folder.join("state.json");
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        // Fichier temporaire laissé par une écriture interrompue.
+        fs::write(folder.join("state.json.tmp"), "partial").unwrap();
+
+        write_private_file(&path, "{\"stack_name\":\"server\"}").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"stack_name\":\"server\"}");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!folder.join("state.json.tmp").exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use crate::ClusterConfig;
+use crate::config::config::NodeConfig;
+use crate::config::state::{self, ClusterState};
+use crate::docker::{cluster, nodes, registry};
+use crate::services::services::{
+    create_docker_file, diff_compose, print_docker_file, project_image, render_docker_file,
+};
+use crate::utils::sync;
+use std::fs;
+
+// Met à jour un cluster en cours d'exécution à partir du fichier de définition : les nodes
+// nouvellement déclarées rejoignent le swarm puis seuls les services modifiés sont redéployés.
+pub fn apply(config: &mut ClusterConfig, state: &mut ClusterState) -> Result<(), String> {
+    config.ip_adress = Some(state.advertise_addr.clone());
+    // Un cluster démarré avec --sync-project le reste.
+    config.sync_project |= state.sync_project;
+    state.sync_project = config.sync_project;
+
+    join_new_nodes(config, state)?;
+
+    let docker_file_path = config.compose_file_path.clone();
+    let deployed_contents = fs::read_to_string(&docker_file_path).unwrap_or_default();
+    let deployed_hash = state::hash_file(&docker_file_path);
+
+    println!("Generating docker-compose file...");
+    let new_contents = render_docker_file(config)?;
+    if config.dry_run {
+        print_docker_file(&docker_file_path, &new_contents);
+    } else {
+        create_docker_file(&docker_file_path, &new_contents).map_err(|e| e.to_string())?;
+    }
+
+    state.services = config.services.clone();
+    state.docker_images = config.docker_images.clone();
+
+    // Le code du projet a pu changer sans que le fichier docker-compose ne change.
+    registry::build_project_image(config)?;
+    if config.sync_project {
+        sync_project(config, state)?;
+    }
+
+    if new_contents == deployed_contents && state.compose_hash == deployed_hash {
+        if project_image(config).is_some() {
+            println!("Services are up to date, redeploying the project image.");
+            registry::push_project_image(config)?;
+            registry::update_project_service(config)?;
+        } else {
+            println!("Services are up to date, nothing to redeploy.");
+        }
+        return state.save();
+    }
+
+    let diff = diff_compose(&deployed_contents, &new_contents)?;
+    for name in &diff.added {
+        println!("  + {}", name);
+    }
+    for name in &diff.changed {
+        println!("  ~ {}", name);
+    }
+    for name in &diff.removed {
+        println!("  - {}", name);
+    }
+    if diff.is_empty() {
+        println!("  ~ stack settings (networks, volumes)");
+    }
+
+    println!("Pulling docker images... This may take a while.");
+    let node_configs = state.node_configs(config);
+    let nodes: Vec<&NodeConfig> = node_configs.iter().collect();
+    let results = config.pull_docker_images(&nodes, &state.hostnames())?;
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|result| result.result.is_err())
+        .map(|result| result.ip.as_str())
+        .collect();
+    if !failed.is_empty() {
+        // Le déploiement attend que chaque node dispose des images qu'elle peut exécuter.
+        state.save()?;
+        return Err(format!(
+            "Failed to pull the images on {}. Fix the issue then run `apply` again.",
+            failed.join(", ")
+        ));
+    }
+
+    // Swarm ne met à jour que les services dont la définition a changé, `--prune` supprime
+    // ceux qui ont disparu du fichier.
+    println!("Deploying services to the cluster...");
+    if !cluster::deploy_services(config, !diff.removed.is_empty()) {
+        state.save()?;
+        return Err(String::from("Failed to deploy the services"));
+    }
+
+    // Le registre a pu être ajouté par ce déploiement, l'image n'est poussée qu'ensuite.
+    registry::push_project_image(config)?;
+    registry::update_project_service(config)?;
+
+    state.compose_hash = state::hash_file(&docker_file_path);
+    state.save()
+}
+
+// Recopie sur toutes les nodes les fichiers du projet modifiés depuis la dernière copie.
+fn sync_project(config: &ClusterConfig, state: &ClusterState) -> Result<(), String> {
+    println!("Copying the project to the nodes...");
+    let node_configs = state.node_configs(config);
+    let nodes: Vec<&NodeConfig> = node_configs.iter().collect();
+    let results = sync::sync_project(config, &nodes);
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|result| result.result.is_err())
+        .map(|result| result.ip.as_str())
+        .collect();
+    if !failed.is_empty() {
+        state.save()?;
+        return Err(format!(
+            "Failed to copy the project to {}. Fix the issue then run `apply` again.",
+            failed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+// Fait rejoindre le swarm aux nodes déclarées dans le fichier de définition mais absentes
+// de l'état du cluster.
+fn join_new_nodes(config: &mut ClusterConfig, state: &mut ClusterState) -> Result<(), String> {
+    let new_nodes: Vec<NodeConfig> = config
+        .nodes_configs
+        .iter()
+        .filter(|node_config| !state.nodes.iter().any(|node_state| node_state.ip == node_config.ip))
+        .cloned()
+        .collect();
+
+    if new_nodes.is_empty() {
+        println!("No new node to join.");
+        return Ok(());
+    }
+
+    // Les jetons ont pu être renouvelés depuis le démarrage du cluster.
+    config.fetch_join_commands();
+    if config.manager_join_command.is_empty() || config.worker_join_command.is_empty() {
+        return Err(String::from("Failed to fetch the swarm join commands"));
+    }
+    state.manager_join_command = config.manager_join_command.clone();
+    state.worker_join_command = config.worker_join_command.clone();
+
+    let nodes: Vec<&NodeConfig> = new_nodes.iter().collect();
+    println!("New node(s) : {}", nodes::node_ips(&nodes));
+
+    nodes::join_nodes(config, state, &new_nodes)
+        .map_err(|e| format!("{}. Fix the issue then run `apply` again.", e))
+}
@@ -0,0 +1,299 @@
+use crate::ClusterConfig;
+use crate::docker::cluster::ImageArchive;
+use crate::services::services::{
+    ServerType, pin_registry, project_image, read_bind_mounts, render_docker_file,
+};
+use crate::utils::envVariables::EnvVariables;
+use crate::utils::platform::OsInfo;
+use crate::utils::runner::CommandRunner;
+use crate::utils::ssh::shell_quote;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+const MANIFEST_FILE: &str = "bundle.json";
+const COMPOSE_FILE: &str = "docker-compose.yml";
+
+// Contenu d'un bundle hors ligne, les chemins sont relatifs à sa racine.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BundleManifest {
+    // Version du moteur docker des paquets, None pour celle des dépôts des distributions.
+    #[serde(default)]
+    pub docker_version: Option<String>,
+    #[serde(default)]
+    pub images: BTreeMap<String, BundleImage>,
+    // Dossier des paquets docker de chaque distribution (debian-12).
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+    // Fichiers montés dans les services, copiés sous assets/ avec leur chemin absolu.
+    #[serde(default)]
+    pub assets: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BundleImage {
+    pub id: String,
+    pub file: String,
+}
+
+// Bundle extrait dans le dossier de configuration.
+pub struct Bundle {
+    pub folder: PathBuf,
+    pub manifest: BundleManifest,
+}
+
+impl Bundle {
+    pub fn image_archive(&self, docker_image: &str) -> Option<ImageArchive> {
+        self.manifest.images.get(docker_image).map(|bundle_image| ImageArchive {
+            id: bundle_image.id.clone(),
+            path: self.folder.join(&bundle_image.file),
+        })
+    }
+
+    pub fn packages_folder(&self, os_info: &OsInfo) -> Option<PathBuf> {
+        self.manifest
+            .packages
+            .get(&os_info.bundle_key())
+            .map(|folder| self.folder.join(folder))
+    }
+}
+
+// Prépare tout ce qu'il faut pour démarrer le cluster sans accès à internet : fichier
+// docker-compose, archives des images, paquets docker des distributions `distros` (images
+// docker de ces distributions, debian:12) et fichiers montés dans les services.
+pub fn create_bundle(config: &mut ClusterConfig, out: &Path, distros: &[String]) -> Result<(), String> {
+    check_offline_build(config)?;
+    let env = EnvVariables {};
+    let folder = PathBuf::from(env.get_bundle_folder_path());
+    prepare_folder(config, &folder)?;
+    let mut manifest = BundleManifest {
+        docker_version: config.docker_version.clone(),
+        ..BundleManifest::default()
+    };
+
+    println!("Generating docker-compose file...");
+    let compose = render_docker_file(config)?;
+    write_file(config, &folder.join(COMPOSE_FILE), &compose)?;
+
+    let mut docker_images = config.docker_images.clone();
+    docker_images.sort();
+    docker_images.dedup();
+
+    println!("Pulling docker images... This may take a while.");
+    let failed_images = config.pull_local_images(&docker_images);
+    if !failed_images.is_empty() {
+        return Err(format!("Failed to pull {}", failed_images.join(", ")));
+    }
+    for (docker_image, archive) in config.save_images(&docker_images, &folder.join("images"))? {
+        let file_name = archive.path.file_name().unwrap_or_default().to_string_lossy();
+        manifest.images.insert(
+            docker_image,
+            BundleImage {
+                id: archive.id,
+                file: format!("images/{}", file_name),
+            },
+        );
+    }
+
+    if distros.is_empty() {
+        println!("No --distro given, the nodes must already have docker installed.");
+    }
+    for distro in distros {
+        println!("Downloading docker packages for {}...", distro);
+        let key = download_packages(config, distro, &folder.join("packages"))?;
+        manifest.packages.insert(key.clone(), format!("packages/{}", key));
+    }
+
+    // L'image du projet est construite au démarrage, à partir du dossier du projet.
+    let mut assets = read_bind_mounts(&compose)?;
+    if project_image(config).is_some()
+        && let Some(project_folder_path) = &config.project_folder_path
+        && let Ok(project_folder_path) = fs::canonicalize(project_folder_path)
+    {
+        assets.push(project_folder_path.to_string_lossy().to_string());
+    }
+
+    for asset in assets {
+        if !Path::new(&asset).exists() {
+            println!("Warning: {} does not exist, it is not bundled", asset);
+            continue;
+        }
+        println!("Bundling {}...", asset);
+        let mut cmd = Command::new("cp");
+        cmd.arg("-a").arg("--parents").arg(&asset).arg(folder.join("assets"));
+        run(config.runner.as_ref(), cmd, Duration::from_secs(300))?;
+        manifest.assets.push(asset);
+    }
+
+    let contents = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Failed to serialize the bundle manifest : {}", e))?;
+    write_file(config, &folder.join(MANIFEST_FILE), &contents)?;
+
+    println!("Writing bundle {}...", out.display());
+    let mut cmd = Command::new("tar");
+    cmd.arg("-cf").arg(out).arg("-C").arg(&folder).arg(".");
+    run(config.runner.as_ref(), cmd, Duration::from_secs(1800))?;
+
+    if !config.dry_run {
+        let _ = fs::remove_dir_all(&folder);
+    }
+    println!("Bundle written to {}", out.display());
+    Ok(())
+}
+
+// Extrait un bundle pour `start --bundle` : son fichier docker-compose devient celui du
+// cluster, les fichiers montés absents de la machine sont restaurés et les images et paquets
+// docker seront pris dans le bundle.
+pub fn open_bundle(config: &mut ClusterConfig, bundle_path: &Path) -> Result<(), String> {
+    check_offline_build(config)?;
+    let env = EnvVariables {};
+    let folder = PathBuf::from(env.get_bundle_folder_path());
+    prepare_folder(config, &folder)?;
+
+    println!("Extracting bundle {}...", bundle_path.display());
+    let mut cmd = Command::new("tar");
+    cmd.arg("-xf").arg(bundle_path).arg("-C").arg(&folder);
+    run(config.runner.as_ref(), cmd, Duration::from_secs(1800))?;
+
+    if config.dry_run {
+        println!("[dry-run] bundle manifest not read");
+        config.bundle = Some(Bundle {
+            folder,
+            manifest: BundleManifest::default(),
+        });
+        return Ok(());
+    }
+
+    let manifest_path = folder.join(MANIFEST_FILE);
+    let contents = fs::read_to_string(&manifest_path)
+        .map_err(|e| format!("Failed to read {} : {}", manifest_path.display(), e))?;
+    let manifest: BundleManifest = serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid bundle manifest ({}) : {}", manifest_path.display(), e))?;
+
+    if config.docker_version.is_some() && config.docker_version != manifest.docker_version {
+        println!(
+            "Warning: the bundle contains docker {}, the cluster file expects {}",
+            manifest.docker_version.as_deref().unwrap_or("from the distribution repositories"),
+            config.docker_version.as_deref().unwrap_or_default()
+        );
+    }
+
+    let compose_path = folder.join(COMPOSE_FILE);
+    let mut compose = fs::read_to_string(&compose_path)
+        .map_err(|e| format!("Failed to read {} : {}", compose_path.display(), e))?;
+    if project_image(config).is_some() {
+        compose = pin_registry(&compose, &config.local_hostname()?)?;
+    }
+    fs::write(&config.compose_file_path, compose)
+        .map_err(|e| format!("Failed to copy the docker-compose file of the bundle : {}", e))?;
+
+    for asset in &manifest.assets {
+        if Path::new(asset).exists() {
+            continue;
+        }
+        println!("Restoring {}...", asset);
+        let source = folder.join("assets").join(asset.trim_start_matches('/'));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "mkdir -p \"$(dirname {target})\" && cp -a {source} {target}",
+            target = shell_quote(asset),
+            source = shell_quote(&source.to_string_lossy())
+        ));
+        run(config.runner.as_ref(), cmd, Duration::from_secs(300))?;
+    }
+
+    config.docker_images = manifest.images.keys().cloned().collect();
+    config.bundle = Some(Bundle { folder, manifest });
+    Ok(())
+}
+
+// Le service NodeJS lance `npm install` (à la construction de l'image du projet ou au
+// démarrage du conteneur), ce qui demande un accès au registre npm.
+fn check_offline_build(config: &ClusterConfig) -> Result<(), String> {
+    if config.services.server == Some(ServerType::NodeJS) {
+        return Err(String::from(
+            "The NodeJS server runs `npm install`, which needs the npm registry : it cannot be started from an offline bundle",
+        ));
+    }
+    Ok(())
+}
+
+// Télécharge les paquets docker d'une distribution dans un conteneur de cette distribution.
+// Renvoie la clé des paquets dans le bundle.
+fn download_packages(config: &ClusterConfig, distro: &str, packages_folder: &Path) -> Result<String, String> {
+    let runner = config.runner.as_ref();
+
+    let mut cmd = Command::new("docker");
+    cmd.args(["run", "--rm", distro, "cat", "/etc/os-release"]);
+    let outcome = runner
+        .output(cmd, Duration::from_secs(300))
+        .map_err(|e| format!("Failed to run {} : {}", distro, e))?;
+    if !outcome.success() {
+        return Err(format!("Failed to run {} : {}", distro, outcome.stderr.trim()));
+    }
+    let os_info = OsInfo::parse(&outcome.stdout);
+
+    let key = os_info.bundle_key();
+    let folder = packages_folder.join(&key);
+    if !config.dry_run {
+        fs::create_dir_all(&folder).map_err(|e| format!("Failed to create {} : {}", folder.display(), e))?;
+    }
+
+    // Les paquets sont téléchargés en root dans le conteneur, ils sont rendus à l'utilisateur.
+    // SAFETY: getuid et getgid n'ont pas de préconditions, ne peuvent pas échouer et ne
+    // manipulent aucune mémoire.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let download = format!(
+        "{} && chown -R {}:{} /bundle",
+        os_info.docker_download_command(config.docker_version.as_deref(), "/bundle")?,
+        uid,
+        gid
+    );
+
+    let mut cmd = Command::new("docker");
+    cmd.args(["run", "--rm", "-v"])
+        .arg(format!("{}:/bundle", folder.display()))
+        .args([distro, "sh", "-c", &download]);
+    run(runner, cmd, Duration::from_secs(1800))?;
+
+    Ok(key)
+}
+
+// Repart d'un dossier vide.
+fn prepare_folder(config: &ClusterConfig, folder: &Path) -> Result<(), String> {
+    if config.dry_run {
+        return Ok(());
+    }
+
+    match fs::remove_dir_all(folder) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(format!("Failed to clean {} : {}", folder.display(), e)),
+    }
+    for subfolder in ["images", "packages", "assets"] {
+        fs::create_dir_all(folder.join(subfolder))
+            .map_err(|e| format!("Failed to create {} : {}", folder.display(), e))?;
+    }
+    Ok(())
+}
+
+fn write_file(config: &ClusterConfig, path: &Path, contents: &str) -> Result<(), String> {
+    if config.dry_run {
+        println!("[dry-run] {} not written", path.display());
+        return Ok(());
+    }
+    fs::write(path, contents).map_err(|e| format!("Failed to write {} : {}", path.display(), e))
+}
+
+fn run(runner: &dyn CommandRunner, command: Command, timeout: Duration) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    match runner.run(command, timeout) {
+        Ok(outcome) if outcome.success() => Ok(()),
+        Ok(outcome) if outcome.timed_out => Err(format!("{} timed out after {:?}", program, outcome.duration)),
+        Ok(outcome) => Err(format!("{} failed. Exit code : {:?}", program, outcome.exit_code)),
+        Err(e) => Err(format!("Failed to run {} : {}", program, e)),
+    }
+}
@@ -1,24 +1,63 @@
 use crate::ClusterConfig;
-use crate::utils::command;
+use crate::config::config::{NodeConfig, NodeRole};
+use crate::config::state::{ClusterState, NodeFacts};
 use crate::utils::envVariables::EnvVariables;
+use crate::services::services::{REGISTRY_ADDRESS, ServiceImage, project_image, read_service_images};
+use crate::utils::parallel::{NodeResult, node_log, print_report, run_on_nodes};
+use crate::utils::platform::{detect_os, engine_version_matches};
+use crate::utils::runner::{CommandRunner, RemoteExecutor, describe_command};
+use crate::utils::ssh::{self, shell_quote};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
 use std::time::Duration;
 
-pub fn check_existing_cluster() -> bool {
-    let output = Command::new("docker")
-        .arg("swarm")
-        .arg("ca")
-        .output()
+// Timeout des commandes docker locales rapides (inspection, tokens...).
+const QUICK_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Nom de la stack docker déployée par ClusterNoodle.
+pub const STACK_NAME: &str = "server";
+
+// Node telle que vue par le manager swarm.
+pub struct SwarmNode {
+    pub id: String,
+    pub addr: String,
+    pub hostname: String,
+    pub role: String,
+    pub availability: String,
+    pub state: String,
+    pub engine_version: String,
+}
+
+// Service de la stack déployée tel que vu par le manager swarm.
+pub struct StackService {
+    pub name: String,
+    pub running_replicas: String,
+    pub desired_replicas: String,
+    pub ports: String,
+}
+
+pub fn check_existing_cluster(runner: &dyn CommandRunner) -> bool {
+    // Un cluster démarré par ClusterNoodle laisse un fichier d'état.
+    if let Ok(Some(_)) = ClusterState::load() {
+        return true;
+    }
+
+    let mut cmd = Command::new("docker");
+    cmd.arg("swarm").arg("ca");
+
+    let output = runner
+        .output(cmd, QUICK_COMMAND_TIMEOUT)
         .expect("Erreur durant l'execution de la commande : docker swarm ca");
 
-    let output_str = String::from_utf8_lossy(&output.stderr);
-    if output_str.contains("Error response from daemon") {
+    if output.stderr.contains("Error response from daemon") {
         return false;
     }
 
-    if !output.status.success() {
+    if !output.success() {
         eprintln!("Error:");
-        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        eprintln!("{}", output.stderr);
     }
 
     return true;
@@ -32,15 +71,14 @@ impl ClusterConfig {
         let ip: String;
 
         if ip_adress.is_none() {
-            let ip_output = Command::new("/bin/bash")
-                .arg(usable_ip_script_path)
-                .output()
+            let mut cmd = Command::new("/bin/bash");
+            cmd.arg(usable_ip_script_path);
+            let ip_output = self
+                .runner
+                .output(cmd, QUICK_COMMAND_TIMEOUT)
                 .expect("Failed to get IP address");
 
-            ip = str::from_utf8(&ip_output.stdout)
-                .expect("Invalid UTF-8 output")
-                .trim()
-                .to_string();
+            ip = ip_output.stdout.trim().to_string();
 
             if ip.is_empty() {
                 eprintln!("Could not find a valid network interface (eth, wlp, or virbr)");
@@ -57,197 +95,986 @@ impl ClusterConfig {
     pub fn init_cluster(&mut self) -> () {
 
         // Run docker swarm init with the detected IP
-        let output = Command::new("docker")
-            .arg("swarm")
+        let mut cmd = Command::new("docker");
+        cmd.arg("swarm")
             .arg("init")
             .arg("--advertise-addr")
-            .arg(self.ip_adress.as_ref().expect("IP address not set"))
-            .output()
+            .arg(self.ip_adress.as_ref().expect("IP address not set"));
+        let output = self
+            .runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
             .expect("Failed to run docker swarm init");
 
-        let output_string = String::from_utf8_lossy(&output.stdout).to_string(); // owns the String
+        if !output.success() {
+            eprintln!("Error:");
+            eprintln!("{}", output.stderr);
+        }
 
-        let output_sentences: Vec<&str> = output_string.lines().collect();
+        self.fetch_join_commands();
+    }
 
-        for sentence in output_sentences {
-            if sentence.trim().starts_with("docker swarm join") {
-                self.cluster_docker_command = sentence.trim().to_string();
-            }
-        }
+    // On récupère les commandes permettant de rejoindre le cluster pour chaque rôle.
+    pub fn fetch_join_commands(&mut self) {
+        let advertise_addr = self.ip_adress.clone().unwrap_or_default();
+        self.manager_join_command =
+            fetch_join_command(self.runner.as_ref(), NodeRole::Manager, &advertise_addr);
+        self.worker_join_command =
+            fetch_join_command(self.runner.as_ref(), NodeRole::Worker, &advertise_addr);
+    }
 
-        if !output.status.success() {
-            eprintln!("Error:");
-            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    // Vérification préalable des nodes, docker et les outils nécessaires n'y sont installés
+    // que s'ils manquent. Renvoie aussi ce qui a été constaté sur chaque node.
+    pub fn install_docker(&self, nodes: &[&NodeConfig]) -> (Vec<NodeResult>, BTreeMap<String, NodeFacts>) {
+        let facts = Mutex::new(BTreeMap::new());
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let node_facts = ssh::check_and_install_tools(self, node_config)?;
+            let description = node_facts.describe();
+            facts
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(node_config.ip.clone(), node_facts);
+            Ok(description)
+        });
+
+        print_report("Docker installation", &results);
+        (results, facts.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Vérifie que le manager (machine locale) a la version de docker demandée.
+    pub fn check_local_engine_version(&self) -> Result<(), String> {
+        let Some(expected_version) = &self.docker_version else {
+            return Ok(());
+        };
+
+        let mut cmd = Command::new("docker");
+        cmd.arg("version").arg("--format").arg("{{.Server.Version}}");
+        let output = self
+            .runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
+            .map_err(|e| format!("Failed to get the docker version of the manager : {}", e))?;
+
+        let installed_version = output.stdout.trim();
+        if output.success() && engine_version_matches(installed_version, expected_version) {
+            Ok(())
+        } else {
+            Err(format!(
+                "The manager runs docker {} but docker_version is {}, upgrade it first",
+                if installed_version.is_empty() { "(unknown)" } else { installed_version },
+                expected_version
+            ))
         }
     }
 
-    pub fn install_docker(&mut self) {
-        for node_config in &self.nodes_configs {
-            let target = format!("{}@{}", node_config.username, node_config.ip);
+    // Hostname du manager (machine locale) tel que le swarm le connaît.
+    pub fn local_hostname(&self) -> Result<String, String> {
+        let mut cmd = Command::new("docker");
+        cmd.arg("info").arg("--format").arg("{{.Name}}");
+        let output = self
+            .runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
+            .map_err(|e| format!("Failed to get the hostname of the manager : {}", e))?;
 
-            let mut cmd = Command::new("sshpass");
-            cmd.arg("-p")
-            .arg(&node_config.password)
-            .arg("ssh")
-            .arg("-o")
-            .arg("StrictHostKeyChecking=no")
-            .arg(&target)
-            .arg(format!(
-                "echo {} | sudo -S apt-get update -y && echo {} | sudo -S apt-get install -y docker.io",
-                node_config.password,
-                node_config.password
+        let hostname = output.stdout.trim();
+        if !output.success() || hostname.is_empty() {
+            return Err(format!(
+                "Failed to get the hostname of the manager : {}",
+                output.stderr.trim()
             ));
+        }
+        Ok(hostname.to_string())
+    }
 
-            match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-                Ok(Some(output)) => {
-                    if output.status.success() {
-                        println!("Docker installed on {}", node_config.ip);
-                    } else {
-                        println!(
-                            "Install failed on {}: {}",
-                            node_config.ip,
-                            String::from_utf8_lossy(&output.stderr)
-                        );
+    // L'image du projet est construite avec `--build-context`, qui demande BuildKit : buildx
+    // est vérifié avant que les nodes ne rejoignent le cluster.
+    pub fn check_local_builder(&self) -> Result<(), String> {
+        if project_image(self).is_none() {
+            return Ok(());
+        }
+
+        let mut cmd = Command::new("docker");
+        cmd.arg("buildx").arg("version");
+        let output = self
+            .runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
+            .map_err(|e| format!("Failed to run docker buildx on the manager : {}", e))?;
+        if output.success() {
+            Ok(())
+        } else {
+            Err(String::from(
+                "Building the project image needs docker buildx on the manager, install the docker-buildx-plugin package",
+            ))
+        }
+    }
+
+    // Tire les images des services sur le manager puis, en parallèle, sur chaque node pouvant
+    // exécuter le service d'après ses contraintes de placement, pour que le déploiement
+    // n'attende pas les téléchargements. `hostnames` associe l'adresse d'une node à son nom
+    // d'hôte swarm. Renvoie une erreur si une image manque sur le manager.
+    pub fn pull_docker_images(
+        &self,
+        nodes: &[&NodeConfig],
+        hostnames: &BTreeMap<String, String>,
+    ) -> Result<Vec<NodeResult>, String> {
+        let service_images = self.service_images();
+
+        // Le manager peut exécuter tout service non réservé aux workers ou à une autre machine.
+        // Il lui faut aussi les images retenues à la génération, dont l'image de base du projet.
+        let mut manager_images = images_for_node(&service_images, NodeRole::Manager, None);
+        manager_images.extend(
+            self.docker_images
+                .iter()
+                .filter(|docker_image| !docker_image.starts_with(REGISTRY_ADDRESS))
+                .cloned(),
+        );
+        manager_images.sort();
+        manager_images.dedup();
+        let failed_images = self.pull_local_images(&manager_images);
+
+        // Sans accès à internet, les nodes reçoivent les images du bundle ou celles exportées
+        // par le manager.
+        let needed: Vec<String> = nodes
+            .iter()
+            .flat_map(|node_config| {
+                let hostname = hostnames.get(&node_config.ip).map(String::as_str);
+                images_for_node(&service_images, node_config.role, hostname)
+            })
+            .collect();
+        let mut exported = false;
+        let archives = match &self.bundle {
+            Some(bundle) => needed
+                .iter()
+                .filter_map(|docker_image| Some((docker_image.clone(), bundle.image_archive(docker_image)?)))
+                .collect(),
+            None if self.air_gapped => {
+                let env = EnvVariables {};
+                exported = true;
+                self.save_images(&needed, &PathBuf::from(env.get_images_folder_path()))?
+            }
+            None => BTreeMap::new(),
+        };
+
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let hostname = hostnames.get(&node_config.ip).map(String::as_str);
+            let images = images_for_node(&service_images, node_config.role, hostname);
+            if images.is_empty() {
+                return Ok(String::from("No image to pull"));
+            }
+
+            let session = self.runner.connect(node_config)?;
+            let mut failures = vec![];
+            for (index, docker_image) in images.iter().enumerate() {
+                let progress = format!("[{}/{}] {}", index + 1, images.len(), docker_image);
+                let started_at = std::time::Instant::now();
+                let result = match archives.get(docker_image) {
+                    Some(archive) => load_image(session.as_ref(), archive),
+                    None => session
+                        .exec(&format!("docker pull -q {}", shell_quote(docker_image)))
+                        .map(|output| output.success().then_some("pulled").ok_or(output.stderr)),
+                };
+                match result {
+                    Ok(Ok(action)) => {
+                        node_log(&node_config.ip, &format!("{} {} in {:?}", progress, action, started_at.elapsed()))
+                    }
+                    Ok(Err(stderr)) => {
+                        node_log(&node_config.ip, &format!("{} failed : {}", progress, stderr.trim()));
+                        failures.push(docker_image.clone());
                     }
+                    Err(e) => return Err(e),
                 }
-                Ok(None) => println!("Timeout for {}", node_config.ip),
-                Err(e) => println!("Execution failed on {}: {}", node_config.ip, e),
+            }
+
+            if failures.is_empty() {
+                Ok(format!("{} image(s) ready", images.len()))
+            } else {
+                Err(format!("Failed to pull {}", failures.join(", ")))
+            }
+        });
+        if exported && !self.dry_run {
+            for archive in archives.values() {
+                let _ = std::fs::remove_file(&archive.path);
             }
         }
+        print_report("Pulling images", &results);
+
+        if failed_images.is_empty() {
+            Ok(results)
+        } else {
+            Err(format!("Failed to pull {} on the manager", failed_images.join(", ")))
+        }
     }
 
-    pub fn pull_docker_images(&self){
-        for docker_image in &self.docker_images {
+    // Tire les images sur la machine locale, ou les charge depuis le bundle qui les contient.
+    // Renvoie les images qui n'ont pas pu être obtenues.
+    pub fn pull_local_images(&self, docker_images: &[String]) -> Vec<String> {
+        let mut failed_images = vec![];
+        for docker_image in docker_images {
             let mut cmd = Command::new("docker");
-            cmd.arg("pull");
-            cmd.arg(docker_image);
-            println!("pulling docker image : {}", docker_image);
-
-            match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-                Ok(Some(output)) => {
-                    println!("{}", String::from_utf8_lossy(&output.stdout));
-                    if output.status.success() {
-                        println!("Docker image pulled");
+            match self.bundle.as_ref().and_then(|bundle| bundle.image_archive(docker_image)) {
+                Some(archive) => {
+                    cmd.arg("load").arg("-i").arg(&archive.path);
+                    println!("loading docker image : {}", docker_image);
+                }
+                None => {
+                    cmd.arg("pull");
+                    cmd.arg(docker_image);
+                    println!("pulling docker image : {}", docker_image);
+                }
+            }
+
+            match self.runner.run(cmd, Duration::from_secs(1000)) {
+                Ok(outcome) if outcome.timed_out => {
+                    println!(
+                        "Timeout while pulling following docker image : {} (after {:?})",
+                        docker_image, outcome.duration
+                    );
+                    failed_images.push(docker_image.clone());
+                }
+                Ok(outcome) => {
+                    if outcome.success() {
+                        println!("Docker image pulled in {:?}", outcome.duration);
                     } else {
                         println!(
-                            "Failed to pull following docker image : {}. Error : {}",
-                            &docker_image,
-                            String::from_utf8_lossy(&output.stderr)
+                            "Failed to pull following docker image : {}. Exit code : {:?}",
+                            &docker_image, outcome.exit_code
                         );
+                        failed_images.push(docker_image.clone());
                     }
                 }
-                Ok(None) => println!("Timeout while pulling following docker image : {}", docker_image),
-                Err(e) => println!("Failed to pull following docker image : {}. Error : {}", docker_image, e),
+                Err(e) => {
+                    println!("Failed to pull following docker image : {}. Error : {}", docker_image, e);
+                    failed_images.push(docker_image.clone());
+                }
             }
         }
+        failed_images
     }
 
-    pub fn join_cluster(&self) {
-        for node_config in &self.nodes_configs {
-            let target = format!("{}@{}", node_config.username, node_config.ip);
-            let command = &self.cluster_docker_command;
+    // Exporte les images locales avec `docker save`, une archive par image, dans `images_folder`.
+    pub fn save_images(
+        &self,
+        docker_images: &[String],
+        images_folder: &Path,
+    ) -> Result<BTreeMap<String, ImageArchive>, String> {
+        if !self.dry_run {
+            std::fs::create_dir_all(images_folder)
+                .map_err(|e| format!("Failed to create {} : {}", images_folder.display(), e))?;
+        }
 
-            let mut cmd = Command::new("ssh");
-            cmd.arg("-o")
-                .arg("StrictHostKeyChecking=no")
-                .arg(&target)
-                .arg(format!("{}", command));
+        let mut archives = BTreeMap::new();
+        for docker_image in docker_images {
+            if archives.contains_key(docker_image) {
+                continue;
+            }
 
-            match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-                Ok(Some(output)) => {
-                    println!("{}", String::from_utf8_lossy(&output.stdout));
-                    if output.status.success() {
-                        println!("{} joined the cluster", node_config.ip);
-                    } else {
-                        println!(
-                            "{} failed to join the cluster : {}",
-                            node_config.ip,
-                            String::from_utf8_lossy(&output.stderr)
-                        );
-                    }
+            let mut cmd = Command::new("docker");
+            cmd.args(["image", "inspect", "--format", "{{.Id}}", docker_image]);
+            let outcome = self
+                .runner
+                .output(cmd, Duration::from_secs(30))
+                .map_err(|e| format!("Failed to inspect {} : {}", docker_image, e))?;
+            if !outcome.success() {
+                return Err(format!("{} is not available on the manager : {}", docker_image, outcome.stderr.trim()));
+            }
+
+            let file_name: String = docker_image
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+                .collect();
+            let path = images_folder.join(format!("{}.tar", file_name));
+
+            println!("Exporting docker image : {}", docker_image);
+            let mut cmd = Command::new("docker");
+            cmd.arg("save").arg("-o").arg(&path).arg(docker_image);
+            match self.runner.run(cmd, Duration::from_secs(1000)) {
+                Ok(outcome) if outcome.success() => (),
+                Ok(outcome) => {
+                    return Err(format!("Failed to export {}. Exit code : {:?}", docker_image, outcome.exit_code));
                 }
-                Ok(None) => println!("Timeout for execution of {} on : {}", command, node_config.ip),
-                Err(e) => println!("Execution of {} failed on {} : {}", command, node_config.ip, e),
+                Err(e) => return Err(format!("Failed to export {} : {}", docker_image, e)),
             }
+
+            archives.insert(
+                docker_image.clone(),
+                ImageArchive {
+                    id: outcome.stdout.trim().to_string(),
+                    path,
+                },
+            );
         }
+        Ok(archives)
     }
 
-    pub fn leave_cluster(&mut self) {
-        for node_config in &self.nodes_configs {
-            let target = format!("{}@{}", node_config.username, node_config.ip);
-            let command = "docker swarm leave --force";
+    // Images des services du fichier docker-compose déployé, avec leurs contraintes de
+    // placement. À défaut de fichier lisible, les images retenues lors de la génération.
+    fn service_images(&self) -> Vec<ServiceImage> {
+        let from_config = || {
+            self.docker_images
+                .iter()
+                .map(|docker_image| ServiceImage {
+                    service: String::new(),
+                    image: docker_image.clone(),
+                    constraints: vec![],
+                })
+                .collect()
+        };
 
-            let mut cmd = Command::new("ssh");
-            cmd.arg("-o")
-                .arg("StrictHostKeyChecking=no")
-                .arg(&target)
-                .arg(format!("{}", command));
+        // En simulation le fichier n'est pas écrit, celui présent peut être périmé.
+        if self.dry_run && !self.docker_images.is_empty() {
+            return from_config();
+        }
 
-            match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-                Ok(Some(output)) => {
-                    println!("{}", String::from_utf8_lossy(&output.stdout));
-                    if output.status.success() {
-                        println!("{} left the cluster", node_config.ip);
-                    } else {
-                        println!(
-                            "{} failed to leave the cluster : {}",
-                            node_config.ip,
-                            String::from_utf8_lossy(&output.stderr)
-                        );
-                    }
+        // L'image du projet est construite sur le manager puis tirée du registre du cluster
+        // au déploiement.
+        match read_service_images(&self.compose_file_path) {
+            Ok(service_images) => service_images
+                .into_iter()
+                .filter(|service_image| !service_image.image.starts_with(REGISTRY_ADDRESS))
+                .collect(),
+            Err(e) => {
+                eprintln!("Warning: {}, pulling the images of the generated services", e);
+                from_config()
+            }
+        }
+    }
+
+    pub fn join_cluster(&self, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+        self.warn_on_manager_quorum();
+
+        // Les managers rejoignent le cluster un par un avant les workers pour ne pas
+        // perturber le consensus, les workers rejoignent ensuite en parallèle.
+        let managers: Vec<&NodeConfig> = nodes
+            .iter()
+            .copied()
+            .filter(|node_config| node_config.role == NodeRole::Manager)
+            .collect();
+        let workers: Vec<&NodeConfig> = nodes
+            .iter()
+            .copied()
+            .filter(|node_config| node_config.role == NodeRole::Worker)
+            .collect();
+
+        let mut results = run_on_nodes(&managers, 1, |node_config| self.join_node(node_config));
+        results.extend(run_on_nodes(&workers, self.parallelism, |node_config| {
+            self.join_node(node_config)
+        }));
+
+        print_report("Joining the cluster", &results);
+        results
+    }
+
+    fn join_node(&self, node_config: &NodeConfig) -> Result<String, String> {
+        let command = match node_config.role {
+            NodeRole::Manager => &self.manager_join_command,
+            NodeRole::Worker => &self.worker_join_command,
+        };
+
+        if command.is_empty() {
+            return Err(format!("No join command available for {:?} nodes", node_config.role));
+        }
+
+        let session = self.runner.connect(node_config)?;
+
+        // Une node déjà membre du swarm (reprise d'un démarrage interrompu) n'a rien à faire.
+        let swarm_state = session.exec("docker info --format '{{.Swarm.LocalNodeState}}'")?;
+        if swarm_state.success() && swarm_state.stdout.trim() == "active" {
+            return Ok(String::from("Already part of the cluster"));
+        }
+
+        let output = session.exec(command)?;
+        if output.success() {
+            Ok(format!("{}\nJoined the cluster as {:?}", output.stdout, node_config.role))
+        } else {
+            Err(format!("Failed to join the cluster : {}", output.stderr))
+        }
+    }
+
+    // La machine locale est toujours manager, le consensus Raft a besoin d'un nombre
+    // impair de managers (au moins 3) pour tolérer la perte de l'un d'entre eux.
+    fn warn_on_manager_quorum(&self) {
+        let managers_count = 1 + self
+            .nodes_configs
+            .iter()
+            .filter(|node_config| node_config.role == NodeRole::Manager)
+            .count();
+
+        if managers_count < 3 {
+            println!(
+                "Warning: the cluster has {} manager(s), losing one of them will make the swarm unavailable. Declare at least 3 managers to keep a quorum.",
+                managers_count
+            );
+        } else if managers_count % 2 == 0 {
+            println!(
+                "Warning: the cluster has an even number of managers ({}), which does not improve fault tolerance. Use an odd number of managers.",
+                managers_count
+            );
+        }
+    }
+
+    pub fn leave_cluster(&self, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let session = self.runner.connect(node_config)?;
+            let output = session.exec("docker swarm leave --force")?;
+            if output.success() {
+                Ok(format!("{}\nLeft the cluster", output.stdout))
+            } else {
+                Err(format!("Failed to leave the cluster : {}", output.stderr))
+            }
+        });
+
+        print_report("Leaving the cluster", &results);
+        results
+    }
+
+    // Attend que les conteneurs de la stack soient arrêtés sur chaque node : le `docker ps`
+    // du manager ne voit pas ceux qui s'arrêtent encore sur les autres machines.
+    pub fn wait_for_stack_containers(&self, nodes: &[&NodeConfig], timeout: Duration) -> Vec<NodeResult> {
+        let command = format!("docker ps -q --filter label=com.docker.stack.namespace={}", STACK_NAME);
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let session = self.runner.connect(node_config)?;
+            let started_at = std::time::Instant::now();
+
+            loop {
+                let output = session.exec(&command)?;
+                if !output.success() {
+                    return Err(format!("Failed to list the containers of stack {} : {}", STACK_NAME, output.stderr.trim()));
+                }
+
+                let remaining = output.stdout.lines().filter(|line| !line.trim().is_empty()).count();
+                if remaining == 0 {
+                    return Ok(String::from("Stack containers stopped"));
+                }
+                if started_at.elapsed() >= timeout {
+                    return Err(format!(
+                        "{} container(s) of stack {} still running after {:?}",
+                        remaining, STACK_NAME, timeout
+                    ));
                 }
-                Ok(None) => println!("Timeout for execution of {} on : {}", command, node_config.ip),
-                Err(e) => println!("Execution of {} failed on {} : {}", command, node_config.ip, e),
+
+                std::thread::sleep(Duration::from_secs(2));
+            }
+        });
+
+        print_report("Stack removal", &results);
+        results
+    }
+
+    // Supprime sur chaque node les volumes nommés de la stack, sauf ceux de `kept_volumes`.
+    pub fn remove_volumes(&self, nodes: &[&NodeConfig], kept_volumes: &[String]) -> Vec<NodeResult> {
+        let command = remove_volumes_command(kept_volumes);
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let session = self.runner.connect(node_config)?;
+            let output = session.exec(&command)?;
+            if output.success() {
+                Ok(format!("{}\nVolumes removed", output.stdout))
+            } else {
+                Err(format!("Failed to remove volumes : {}", output.stderr))
             }
+        });
+
+        print_report("Volumes removal", &results);
+        results
+    }
+
+    // Désinstalle docker et supprime ses données (images, conteneurs, volumes restants).
+    pub fn purge_docker(&self, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+        let results = run_on_nodes(nodes, self.parallelism, |node_config| {
+            let session = self.runner.connect(node_config)?;
+            session.check_sudo()?;
+
+            let os_info = detect_os(session.as_ref())?;
+            let command = format!("{} && rm -rf /var/lib/docker", os_info.docker_purge_command()?);
+            let output = session.exec_sudo(&command)?;
+            if output.success() {
+                Ok(String::from("Docker purged"))
+            } else {
+                Err(format!("Purge failed : {}", output.stderr))
+            }
+        });
+
+        print_report("Docker purge", &results);
+        results
+    }
+}
+
+// Image exportée par le manager pour les nodes sans accès à internet.
+pub struct ImageArchive {
+    // Identifiant de l'image (sha256:...), pour ne pas la transférer à une node qui l'a déjà.
+    pub id: String,
+    pub path: PathBuf,
+}
+
+// Charge une image exportée sur la node, sauf si elle y est déjà présente. L'archive est
+// envoyée sur l'entrée standard de `docker load` au fil de la lecture.
+fn load_image(session: &dyn RemoteExecutor, archive: &ImageArchive) -> Result<Result<&'static str, String>, String> {
+    if !archive.id.is_empty() {
+        let output = session.exec(&format!("docker image inspect --format '{{{{.Id}}}}' {}", shell_quote(&archive.id)))?;
+        if output.success() && output.stdout.trim() == archive.id {
+            return Ok(Ok("already present"));
         }
     }
+
+    let output = session.exec_with_file("docker load -q", &archive.path)?;
+    Ok(output.success().then_some("loaded").ok_or(output.stderr))
+}
+
+// Images distinctes des services qu'une machine de ce rôle et de ce nom d'hôte peut exécuter.
+fn images_for_node(service_images: &[ServiceImage], role: NodeRole, hostname: Option<&str>) -> Vec<String> {
+    let mut images: Vec<String> = service_images
+        .iter()
+        .filter(|service_image| {
+            service_image
+                .constraints
+                .iter()
+                .all(|constraint| placement_allows(constraint, role, hostname))
+        })
+        .map(|service_image| service_image.image.clone())
+        .collect();
+    images.sort();
+    images.dedup();
+    images
+}
+
+// Évalue une contrainte de placement swarm (`node.role == manager`, `node.hostname != web1`).
+// Les contraintes qui ne peuvent pas être évaluées ici (labels, plateforme) sont considérées
+// comme satisfaites : mieux vaut tirer une image inutile qu'en manquer une.
+fn placement_allows(constraint: &str, role: NodeRole, hostname: Option<&str>) -> bool {
+    let (attribute, expected, equal) = if let Some((attribute, expected)) = constraint.split_once("!=") {
+        (attribute, expected, false)
+    } else if let Some((attribute, expected)) = constraint.split_once("==") {
+        (attribute, expected, true)
+    } else {
+        return true;
+    };
+
+    let actual = match attribute.trim() {
+        "node.role" => match role {
+            NodeRole::Manager => "manager",
+            NodeRole::Worker => "worker",
+        },
+        "node.hostname" => match hostname {
+            Some(hostname) => hostname,
+            None => return true,
+        },
+        _ => return true,
+    };
+
+    (actual == expected.trim()) == equal
+}
+
+// Commande listant puis supprimant les volumes nommés de la stack présents sur une machine.
+fn remove_volumes_command(kept_volumes: &[String]) -> String {
+    let mut command = format!(
+        "docker volume ls -q --filter label=com.docker.stack.namespace={}",
+        STACK_NAME
+    );
+    for volume in kept_volumes {
+        command.push_str(&format!(" | grep -vxF '{}'", volume));
+    }
+    command.push_str(" | xargs -r docker volume rm");
+    command
+}
+
+// Supprime la stack déployée : services, réseaux overlay et secrets créés par la stack.
+pub fn remove_stack(runner: &dyn CommandRunner) -> Result<(), String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("stack").arg("rm").arg(STACK_NAME);
+    run_manager_command(runner, cmd)
 }
 
-pub fn destroy_cluster() -> () {
-    let output = Command::new("docker")
-        .arg("swarm")
-        .arg("leave")
-        .arg("--force")
-        .output()
+// Attend que toutes les tâches et réseaux de la stack aient disparu. `docker stack rm`
+// rend la main avant que les conteneurs ne soient arrêtés.
+pub fn wait_for_stack_removal(runner: &dyn CommandRunner, timeout: Duration) -> Result<(), String> {
+    let started_at = std::time::Instant::now();
+
+    loop {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "docker service ls -q --filter label=com.docker.stack.namespace={stack}; docker ps -q --filter label=com.docker.stack.namespace={stack}; docker network ls -q --filter label=com.docker.stack.namespace={stack}",
+            stack = STACK_NAME
+        ));
+        let output = runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
+            .map_err(|e| format!("Failed to list the remains of stack {} : {}", STACK_NAME, e))?;
+
+        let remaining = output.stdout.lines().filter(|line| !line.trim().is_empty()).count();
+        if remaining == 0 {
+            return Ok(());
+        }
+        if started_at.elapsed() >= timeout {
+            return Err(format!(
+                "{} service(s), container(s) or network(s) of stack {} still present after {:?}",
+                remaining, STACK_NAME, timeout
+            ));
+        }
+
+        std::thread::sleep(Duration::from_secs(2));
+    }
+}
+
+// Supprime les volumes nommés de la stack présents sur la machine locale.
+pub fn remove_local_volumes(runner: &dyn CommandRunner, kept_volumes: &[String]) -> Result<(), String> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(remove_volumes_command(kept_volumes));
+    run_manager_command(runner, cmd)
+}
+
+// Liste les nodes du swarm avec leur adresse, à exécuter sur un manager.
+pub fn fetch_swarm_nodes(runner: &dyn CommandRunner) -> Vec<SwarmNode> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(
+        "docker node inspect $(docker node ls -q) --format '{{.ID}} {{.Status.Addr}} {{.Description.Hostname}} {{.Spec.Role}} {{.Spec.Availability}} {{.Status.State}} {{.Description.Engine.EngineVersion}}'",
+    );
+
+    let output = match runner.output(cmd, QUICK_COMMAND_TIMEOUT) {
+        Ok(output) if output.success() => output,
+        Ok(output) => {
+            eprintln!("Failed to list swarm nodes : {}", output.stderr);
+            return vec![];
+        }
+        Err(e) => {
+            eprintln!("Failed to list swarm nodes : {}", e);
+            return vec![];
+        }
+    };
+
+    output
+        .stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(SwarmNode {
+                id: fields.next()?.to_string(),
+                addr: fields.next()?.to_string(),
+                hostname: fields.next().unwrap_or_default().to_string(),
+                role: fields.next().unwrap_or_default().to_string(),
+                availability: fields.next().unwrap_or_default().to_string(),
+                state: fields.next().unwrap_or_default().to_string(),
+                engine_version: fields.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn fetch_stack_services(runner: &dyn CommandRunner) -> Result<Vec<StackService>, String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("stack")
+        .arg("services")
+        .arg(STACK_NAME)
+        .arg("--format")
+        .arg("{{.Name}}|{{.Replicas}}|{{.Ports}}");
+
+    let output = runner
+        .output(cmd, QUICK_COMMAND_TIMEOUT)
+        .map_err(|e| format!("Failed to list stack services : {}", e))?;
+    if !output.success() {
+        return Err(format!("Failed to list stack services : {}", output.stderr.trim()));
+    }
+
+    Ok(output
+        .stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(3, '|');
+            let name = fields.next().unwrap_or_default().to_string();
+            // Format "en cours/souhaitées", éventuellement suivi d'une contrainte "(max 1 per node)".
+            let replicas = fields.next().unwrap_or_default();
+            let replicas = replicas.split_whitespace().next().unwrap_or_default();
+            let (running_replicas, desired_replicas) = replicas.split_once('/').unwrap_or((replicas, ""));
+            StackService {
+                name,
+                running_replicas: running_replicas.to_string(),
+                desired_replicas: desired_replicas.to_string(),
+                ports: fields.next().unwrap_or_default().to_string(),
+            }
+        })
+        .collect())
+}
+
+// Change la disponibilité d'une node (active, pause, drain), à exécuter sur un manager.
+pub fn set_node_availability(runner: &dyn CommandRunner, node_id: &str, availability: &str) -> Result<(), String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("node").arg("update").arg("--availability").arg(availability).arg(node_id);
+    run_manager_command(runner, cmd)
+}
+
+pub fn demote_node(runner: &dyn CommandRunner, node_id: &str) -> Result<(), String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("node").arg("demote").arg(node_id);
+    run_manager_command(runner, cmd)
+}
+
+pub fn remove_swarm_node(runner: &dyn CommandRunner, node_id: &str) -> Result<(), String> {
+    let mut cmd = Command::new("docker");
+    cmd.arg("node").arg("rm").arg("--force").arg(node_id);
+    run_manager_command(runner, cmd)
+}
+
+// Attend que plus aucune tâche ne tourne sur la node : après un drain, swarm arrête ses
+// tâches et les replanifie sur les autres nodes.
+pub fn wait_for_node_tasks(runner: &dyn CommandRunner, node_id: &str, timeout: Duration) -> Result<(), String> {
+    let started_at = std::time::Instant::now();
+
+    loop {
+        let mut cmd = Command::new("docker");
+        cmd.arg("node")
+            .arg("ps")
+            .arg(node_id)
+            .arg("--format")
+            .arg("{{.CurrentState}}");
+        let output = runner
+            .output(cmd, QUICK_COMMAND_TIMEOUT)
+            .map_err(|e| format!("Failed to list the tasks of {} : {}", node_id, e))?;
+        if !output.success() {
+            return Err(format!("Failed to list the tasks of {} : {}", node_id, output.stderr.trim()));
+        }
+
+        let running_tasks = output.stdout.lines().filter(|line| line.starts_with("Running")).count();
+        if running_tasks == 0 {
+            return Ok(());
+        }
+        if started_at.elapsed() >= timeout {
+            return Err(format!("{} task(s) still running on {} after {:?}", running_tasks, node_id, timeout));
+        }
+
+        println!("Waiting for {} task(s) to be rescheduled...", running_tasks);
+        std::thread::sleep(Duration::from_secs(5));
+    }
+}
+
+fn run_manager_command(runner: &dyn CommandRunner, cmd: Command) -> Result<(), String> {
+    let description = describe_command(&cmd);
+    let output = runner
+        .output(cmd, QUICK_COMMAND_TIMEOUT)
+        .map_err(|e| format!("Failed to run {} : {}", description, e))?;
+    if output.success() {
+        Ok(())
+    } else if output.truncated {
+        Err(format!("{} failed : (output truncated) {}", description, output.stderr.trim()))
+    } else {
+        Err(format!("{} failed : {}", description, output.stderr.trim()))
+    }
+}
+
+// Récupère la commande `docker swarm join` à exécuter sur une node pour le rôle donné.
+fn fetch_join_command(runner: &dyn CommandRunner, role: NodeRole, advertise_addr: &str) -> String {
+    let role_arg = match role {
+        NodeRole::Manager => "manager",
+        NodeRole::Worker => "worker",
+    };
+
+    let mut cmd = Command::new("docker");
+    cmd.arg("swarm").arg("join-token").arg("-q").arg(role_arg);
+    let output = runner
+        .output(cmd, QUICK_COMMAND_TIMEOUT)
+        .expect("Failed to run docker swarm join-token");
+
+    if !output.success() {
+        eprintln!("Error while fetching the {} join token:", role_arg);
+        eprintln!("{}", output.stderr);
+        return String::new();
+    }
+
+    format!("docker swarm join --token {} {}:2377", output.stdout.trim(), advertise_addr)
+}
+
+pub fn destroy_cluster(runner: &dyn CommandRunner) -> () {
+    let mut cmd = Command::new("docker");
+    cmd.arg("swarm").arg("leave").arg("--force");
+    let output = runner
+        .run(cmd, QUICK_COMMAND_TIMEOUT)
         .expect("Failed to execute command : docker swarm leave --force");
 
-    println!("{}", String::from_utf8_lossy(&output.stdout));
-    if !output.status.success() {
+    if !output.success() {
         eprintln!("Error:");
-        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        eprintln!("{}", output.stderr);
     }
 }
 
-pub fn deploy_services() {
-    // Si un chemin a été renseigné on l'utilise sinon utilise celui par défaut.
-    let env = EnvVariables {};
-    let docker_file_path = String::from(env.get_docker_file_path());
-
+// Avec `prune`, les services qui ne sont plus dans le fichier docker-compose sont supprimés.
+pub fn deploy_services(config: &ClusterConfig, prune: bool) -> bool {
+    let runner = config.runner.as_ref();
     let mut cmd = Command::new("docker");
     cmd.arg("stack")
         .arg("deploy")
         .arg("-c")
-        .arg(&docker_file_path)
-        .arg("server");
+        .arg(&config.compose_file_path);
+    if prune {
+        cmd.arg("--prune");
+    }
+    cmd.arg(STACK_NAME);
 
-    match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-        Ok(Some(output)) => {
-            println!("{}", String::from_utf8_lossy(&output.stdout));
-            if output.status.success() {
+    match runner.run(cmd, Duration::from_secs(1000)) {
+        Ok(outcome) if outcome.timed_out => {
+            println!("Timeout for execution of 'docker stack deploy -c config.yaml server' ");
+            false
+        }
+        Ok(outcome) => {
+            if outcome.success() {
                 println!("Services deployed.");
             } else {
                 println!(
-                    "Failed to deploy services : {}",
-                    String::from_utf8_lossy(&output.stderr)
+                    "Failed to deploy services (exit code {:?}) : {}",
+                    outcome.exit_code, outcome.stderr
                 );
             }
+            outcome.success()
         }
-        Ok(None) => {
-            println!("Timeout for execution of 'docker stack deploy -c config.yaml server' ")
+        Err(e) => {
+            println!(
+                "Execution of 'docker stack deploy -c config.yaml server' failed: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, recording_config};
+
+    const CLUSTER_FILE: &str = "
+version: 1
+nodes:
+  - ip: 10.0.0.2
+    username: deploy
+    role: manager
+  - ip: 10.0.0.3
+    username: deploy
+    role: manager
+  - ip: 10.0.0.4
+    username: deploy
+";
+
+    fn join(runner: &RecordingRunner) -> Vec<NodeResult> {
+        let mut config = recording_config(CLUSTER_FILE, runner);
+        config.manager_join_command = String::from("docker swarm join --token MANAGER 10.0.0.1:2377");
+        config.worker_join_command = String::from("docker swarm join --token WORKER 10.0.0.1:2377");
+        let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+        config.join_cluster(&nodes)
+    }
+
+    fn node(ip: &str) -> Target {
+        Target::Node(ip.to_string())
+    }
+
+    #[test]
+    fn join_cluster_with_the_command_of_each_role() {
+        let runner = RecordingRunner::new();
+        let results = join(&runner);
+        assert!(results.iter().all(|result| result.result.is_ok()));
+
+        for ip in ["10.0.0.2", "10.0.0.3"] {
+            assert_eq!(
+                runner.recorded_on(&node(ip)),
+                vec![
+                    "docker info --format '{{.Swarm.LocalNodeState}}'",
+                    "docker swarm join --token MANAGER 10.0.0.1:2377"
+                ]
+            );
+        }
+        assert_eq!(runner.recorded_on(&node("10.0.0.4"))[1], "docker swarm join --token WORKER 10.0.0.1:2377");
+
+        // Les managers rejoignent un par un, avant les workers.
+        let joins: Vec<Target> = runner
+            .recorded()
+            .into_iter()
+            .filter(|recorded| recorded.command.starts_with("docker swarm join"))
+            .map(|recorded| recorded.target)
+            .collect();
+        assert_eq!(joins, vec![node("10.0.0.2"), node("10.0.0.3"), node("10.0.0.4")]);
+    }
+
+    #[test]
+    fn join_cluster_skips_nodes_already_in_the_swarm() {
+        let runner = RecordingRunner::new();
+        runner.respond_on(node("10.0.0.3"), "docker info", 0, "active\n", "");
+        let results = join(&runner);
+
+        assert!(results.iter().all(|result| result.result.is_ok()));
+        assert_eq!(runner.recorded_on(&node("10.0.0.3")).len(), 1);
+    }
+
+    #[test]
+    fn join_cluster_reports_failed_nodes() {
+        let runner = RecordingRunner::new();
+        runner.respond_on(node("10.0.0.2"), "docker swarm join", 1, "", "manager unreachable");
+        runner.set_unreachable("10.0.0.4");
+        let results = join(&runner);
+
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|result| result.result.is_err())
+            .map(|result| result.ip.as_str())
+            .collect();
+        assert_eq!(failed, vec!["10.0.0.2", "10.0.0.4"]);
+        // Un manager en échec n'empêche pas les autres nodes de rejoindre le cluster.
+        assert_eq!(runner.recorded_on(&node("10.0.0.3")).len(), 2);
+        assert!(runner.recorded_on(&node("10.0.0.4")).is_empty());
+    }
+
+    #[test]
+    fn placement_allows_each_constraint() {
+        use NodeRole::{Manager, Worker};
+        let cases = [
+            ("node.role == manager", Manager, None, true),
+            ("node.role == manager", Worker, None, false),
+            ("node.role==worker", Worker, None, true),
+            ("node.role != manager", Worker, None, true),
+            ("node.role != manager", Manager, None, false),
+            ("node.hostname == web1", Worker, Some("web1"), true),
+            ("node.hostname == web1", Worker, Some("web2"), false),
+            ("node.hostname != web1", Manager, Some("web1"), false),
+            ("node.hostname != web1", Manager, Some("web2"), true),
+            // Sans nom d'hôte connu, la node tire l'image au cas où.
+            ("node.hostname == web1", Worker, None, true),
+            ("node.hostname != web1", Worker, None, true),
+            // Labels, plateforme et contraintes illisibles ne sont pas évalués.
+            ("node.labels.zone == eu", Worker, Some("web1"), true),
+            ("node.platform.os != linux", Worker, Some("web1"), true),
+            ("engine.labels.gpu == true", Manager, None, true),
+            ("node.role", Worker, None, true),
+        ];
+
+        for (constraint, role, hostname, allowed) in cases {
+            assert_eq!(
+                placement_allows(constraint, role, hostname),
+                allowed,
+                "{} ({:?}, {:?})",
+                constraint,
+                role,
+                hostname
+            );
+        }
+    }
+
+    #[test]
+    fn images_for_node_follows_the_placement_constraints() {
+        let service_image = |image: &str, constraints: &[&str]| ServiceImage {
+            service: String::new(),
+            image: image.to_string(),
+            constraints: constraints.iter().map(|constraint| constraint.to_string()).collect(),
+        };
+        let service_images = [
+            service_image("nginx:1.27", &[]),
+            service_image("postgres:16", &["node.role == manager"]),
+            service_image("registry:2", &["node.hostname == manager-1"]),
+            service_image("agent:1", &["node.role != manager", "node.labels.zone == eu"]),
+            // Deux services de la même image ne la font tirer qu'une fois.
+            service_image("nginx:1.27", &["node.role == worker"]),
+        ];
+
+        let cases = [
+            (NodeRole::Manager, Some("manager-1"), vec!["nginx:1.27", "postgres:16", "registry:2"]),
+            (NodeRole::Manager, Some("manager-2"), vec!["nginx:1.27", "postgres:16"]),
+            (NodeRole::Manager, None, vec!["nginx:1.27", "postgres:16", "registry:2"]),
+            (NodeRole::Worker, Some("worker-1"), vec!["agent:1", "nginx:1.27"]),
+        ];
+
+        for (role, hostname, expected) in cases {
+            assert_eq!(images_for_node(&service_images, role, hostname), expected, "{:?} {:?}", role, hostname);
         }
-        Err(e) => println!(
-            "Execution of 'docker stack deploy -c config.yaml server' failed: {}",
-            e
-        ),
     }
 }
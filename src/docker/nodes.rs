@@ -0,0 +1,519 @@
+use crate::ClusterConfig;
+use crate::config::config::{NodeConfig, NodeRole, add_node_to_cluster_file};
+use crate::config::state::ClusterState;
+use crate::docker::cluster;
+use crate::utils::identity;
+use crate::utils::parallel::{NodeResult, node_log};
+use crate::utils::platform::engine_version_matches;
+use crate::utils::ssh;
+use crate::utils::sync;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Durée maximale laissée à swarm pour replanifier les tâches d'une node drainée.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+// Durée maximale d'attente du démarrage des répliques replanifiées.
+const RESCHEDULE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Installe les clés SSH et docker sur les nodes puis leur fait rejoindre le swarm avec les
+// commandes de jointure de la configuration. Les nodes ayant rejoint le cluster sont
+// enregistrées dans l'état, une erreur liste celles qui ont échoué.
+pub fn join_nodes(config: &ClusterConfig, state: &mut ClusterState, new_nodes: &[NodeConfig]) -> Result<(), String> {
+    let mut nodes: Vec<&NodeConfig> = new_nodes.iter().collect();
+
+    if !identity::check_existing_identity() {
+        identity::generate_identity(config.runner.as_ref());
+    }
+    let results = ssh::copy_ssh_key_to_machines(config, &nodes);
+    nodes.retain(|node_config| succeeded(&results, &node_config.ip));
+
+    let (results, facts) = config.install_docker(&nodes);
+    state.node_facts.extend(facts);
+    nodes.retain(|node_config| succeeded(&results, &node_config.ip));
+
+    let results = config.join_cluster(&nodes);
+    nodes.retain(|node_config| succeeded(&results, &node_config.ip));
+
+    for node_config in &nodes {
+        state.record_joined_node(node_config);
+    }
+    state.set_swarm_nodes(&cluster::fetch_swarm_nodes(config.runner.as_ref()));
+    state.save()?;
+
+    if nodes.len() < new_nodes.len() {
+        let failed: Vec<&NodeConfig> = new_nodes
+            .iter()
+            .filter(|node_config| !nodes.iter().any(|joined| joined.ip == node_config.ip))
+            .collect();
+        return Err(format!("Failed to join {}", node_ips(&failed)));
+    }
+
+    Ok(())
+}
+
+// Node ajoutée par `add-node` et identifiants passés en ligne de commande.
+pub struct NewNode {
+    pub ip: String,
+    pub username: String,
+    pub role: NodeRole,
+    pub port: Option<u16>,
+    pub password: Option<String>,
+    pub sudo_password: Option<String>,
+    pub identity_file: Option<String>,
+    pub ssh_agent: bool,
+}
+
+// Ajoute une node au cluster en cours d'exécution. Les identifiants sont repris du fichier de
+// définition si la node y est déclarée, sinon elle y est ajoutée une fois dans le swarm pour
+// que les autres commandes la retrouvent.
+pub fn add_node(
+    config: &mut ClusterConfig,
+    state: &mut ClusterState,
+    conf_file_path: &str,
+    new_node: &NewNode,
+) -> Result<(), String> {
+    let ip = new_node.ip.as_str();
+    if state.nodes.iter().any(|node_state| node_state.ip == ip) {
+        return Err(format!("{} is already part of the cluster", ip));
+    }
+
+    let declared = config.nodes_configs.iter().find(|node_config| node_config.ip == ip).cloned();
+    let mut node_config = declared
+        .clone()
+        .unwrap_or_else(|| NodeConfig::new(ip, &new_node.username, new_node.role));
+    node_config.username = new_node.username.clone();
+    node_config.role = new_node.role;
+    if let Some(port) = new_node.port {
+        node_config.port = port;
+    }
+    if let Some(password) = &new_node.password {
+        node_config.password = password.clone();
+    }
+    if new_node.sudo_password.is_some() {
+        node_config.sudo_password = new_node.sudo_password.clone();
+    }
+    if new_node.identity_file.is_some() {
+        node_config.identity_file = new_node.identity_file.clone();
+    }
+    node_config.ssh_agent |= new_node.ssh_agent;
+
+    // La node rejoint le swarm avec les commandes enregistrées au démarrage du cluster.
+    config.ip_adress = Some(state.advertise_addr.clone());
+    config.manager_join_command = state.manager_join_command.clone();
+    config.worker_join_command = state.worker_join_command.clone();
+
+    println!("Adding {} to the cluster as {:?}...", ip, node_config.role);
+    join_nodes(config, state, std::slice::from_ref(&node_config))?;
+
+    if declared.is_none() {
+        if config.dry_run {
+            println!("[dry-run] {} not added to {}", ip, conf_file_path);
+        } else {
+            add_node_to_cluster_file(conf_file_path, &node_config)
+                .map_err(|e| format!("{} joined the cluster but {}", ip, e))?;
+        }
+        config.nodes_configs.push(node_config.clone());
+    }
+
+    // Les services qui montent le projet peuvent être planifiés sur la nouvelle node.
+    if config.sync_project || state.sync_project {
+        let results = sync::sync_project(config, &[&node_config]);
+        if !succeeded(&results, ip) {
+            return Err(format!("{} joined the cluster but the project could not be copied to it", ip));
+        }
+    }
+    println!("{} joined the cluster.", ip);
+    Ok(())
+}
+
+// Retire une node du cluster : ses tâches sont replanifiées sur les autres nodes avant
+// qu'elle ne quitte le swarm et soit supprimée de la liste des nodes du manager.
+pub fn remove_node(config: &ClusterConfig, state: &mut ClusterState, ip: &str) -> Result<(), String> {
+    let Some(node_state) = state.nodes.iter().find(|node_state| node_state.ip == ip) else {
+        return Err(format!("{} is not part of the cluster", ip));
+    };
+    // Le rôle effectif est celui avec lequel la node a rejoint le swarm.
+    let role = node_state.role;
+    let node_config = state
+        .node_configs(config)
+        .into_iter()
+        .find(|node_config| node_config.ip == ip)
+        .expect("every recorded node has a configuration");
+
+    let runner = config.runner.as_ref();
+    let swarm_node_id = node_state
+        .swarm_node_id
+        .clone()
+        .or_else(|| {
+            cluster::fetch_swarm_nodes(runner)
+                .into_iter()
+                .find(|swarm_node| swarm_node.addr == ip)
+                .map(|swarm_node| swarm_node.id)
+        });
+
+    match &swarm_node_id {
+        Some(node_id) => {
+            println!("Draining {}...", ip);
+            cluster::set_node_availability(runner, node_id, "drain")?;
+            cluster::wait_for_node_tasks(runner, node_id, DRAIN_TIMEOUT)?;
+
+            // Un manager doit être rétrogradé avant de quitter le swarm pour ne pas casser le quorum.
+            if role == NodeRole::Manager {
+                cluster::demote_node(runner, node_id)?;
+            }
+        }
+        None => println!("{} is not listed by the swarm manager, skipping drain", ip),
+    }
+
+    println!("{} is leaving the cluster...", ip);
+    let results = config.leave_cluster(&[&node_config]);
+    if !succeeded(&results, ip) {
+        println!("{} could not leave the swarm itself, removing it from the manager anyway", ip);
+    }
+
+    if let Some(node_id) = &swarm_node_id {
+        cluster::remove_swarm_node(runner, node_id)?;
+    }
+
+    state.forget_node(ip);
+    state.save()?;
+
+    println!("{} removed from the cluster.", ip);
+    if config.nodes_configs.iter().any(|node_config| node_config.ip == ip) {
+        println!("{} is still declared in the cluster file, `apply` would join it again.", ip);
+    }
+    Ok(())
+}
+
+fn succeeded(results: &[NodeResult], ip: &str) -> bool {
+    results.iter().any(|result| result.ip == ip && result.result.is_ok())
+}
+
+pub fn node_ips(nodes: &[&NodeConfig]) -> String {
+    nodes
+        .iter()
+        .map(|node_config| node_config.ip.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+// Sort une node de la planification (maintenance) : ses tâches sont déplacées sur les autres
+// nodes, puis les services qui n'ont pas retrouvé toutes leurs répliques sont signalés.
+pub fn drain_node(config: &ClusterConfig, state: Option<&ClusterState>, ip: &str) -> Result<(), String> {
+    let runner = config.runner.as_ref();
+    let node_id = resolve_swarm_node_id(config, state, ip)?;
+
+    println!("Draining {}...", ip);
+    cluster::set_node_availability(runner, &node_id, "drain")?;
+    cluster::wait_for_node_tasks(runner, &node_id, DRAIN_TIMEOUT)?;
+
+    // Les tâches replanifiées mettent quelques instants à démarrer sur les autres nodes.
+    let started_at = Instant::now();
+    let degraded = loop {
+        let degraded: Vec<cluster::StackService> = cluster::fetch_stack_services(runner)?
+            .into_iter()
+            .filter(|service| {
+                let running = service.running_replicas.parse::<u32>().unwrap_or(0);
+                let desired = service.desired_replicas.parse::<u32>().unwrap_or(0);
+                running < desired
+            })
+            .collect();
+        if degraded.is_empty() || started_at.elapsed() >= RESCHEDULE_TIMEOUT {
+            break degraded;
+        }
+        thread::sleep(Duration::from_secs(5));
+    };
+
+    if degraded.is_empty() {
+        println!("{} drained, all services have their replicas running.", ip);
+    } else {
+        println!("{} drained, the following services lost replicas :", ip);
+        for service in degraded {
+            println!(
+                "  - {} : {}/{} replicas running",
+                service.name, service.running_replicas, service.desired_replicas
+            );
+        }
+    }
+    Ok(())
+}
+
+// Remet une node drainée dans la planification.
+pub fn activate_node(config: &ClusterConfig, state: Option<&ClusterState>, ip: &str) -> Result<(), String> {
+    let node_id = resolve_swarm_node_id(config, state, ip)?;
+
+    cluster::set_node_availability(config.runner.as_ref(), &node_id, "active")?;
+    println!("{} is active, new tasks can be scheduled on it.", ip);
+    Ok(())
+}
+
+// Identifiant swarm d'une node de l'inventaire (fichier de définition ou état du cluster).
+fn resolve_swarm_node_id(config: &ClusterConfig, state: Option<&ClusterState>, ip: &str) -> Result<String, String> {
+    let declared = config.nodes_configs.iter().any(|node_config| node_config.ip == ip)
+        || state.is_some_and(|state| state.nodes.iter().any(|node_state| node_state.ip == ip));
+    if !declared {
+        return Err(format!("{} is not a node of the cluster", ip));
+    }
+
+    cluster::fetch_swarm_nodes(config.runner.as_ref())
+        .into_iter()
+        .find(|swarm_node| swarm_node.addr == ip)
+        .map(|swarm_node| swarm_node.id)
+        .ok_or_else(|| format!("{} is not part of the swarm", ip))
+}
+
+// Met à jour le moteur docker des nodes vers `docker_version`, une node à la fois : elle est
+// drainée, mise à jour puis réactivée avant de passer à la suivante.
+pub fn upgrade_engine(config: &ClusterConfig, state: &mut ClusterState) -> Result<(), String> {
+    let Some(expected_version) = config.docker_version.as_deref() else {
+        return Err(String::from("Set docker_version in the cluster file to upgrade the engine"));
+    };
+
+    // Le manager est la machine locale, ClusterNoodle n'y installe pas docker : il doit déjà
+    // tourner avec la version attendue avant qu'aucune node ne soit drainée.
+    config.check_local_engine_version()?;
+
+    let runner = config.runner.as_ref();
+    let swarm_nodes = cluster::fetch_swarm_nodes(runner);
+
+    for node_config in state.node_configs(config) {
+        let swarm_node = swarm_nodes.iter().find(|swarm_node| swarm_node.addr == node_config.ip);
+        if let Some(swarm_node) = swarm_node
+            && engine_version_matches(&swarm_node.engine_version, expected_version)
+        {
+            println!("[{}] Docker {} already installed", node_config.ip, swarm_node.engine_version);
+            continue;
+        }
+
+        println!("Upgrading docker on {} to {}...", node_config.ip, expected_version);
+        if let Some(swarm_node) = swarm_node {
+            cluster::set_node_availability(runner, &swarm_node.id, "drain")?;
+            cluster::wait_for_node_tasks(runner, &swarm_node.id, DRAIN_TIMEOUT)?;
+        }
+
+        // En cas d'échec la node reste drainée et la mise à jour s'arrête là.
+        let facts = ssh::check_and_install_tools(config, &node_config).map_err(|e| {
+            format!("{} : {}. The node is left drained.", node_config.ip, e)
+        })?;
+        node_log(&node_config.ip, &facts.describe());
+        state.node_facts.insert(node_config.ip.clone(), facts);
+        state.save()?;
+
+        if let Some(swarm_node) = swarm_node {
+            cluster::set_node_availability(runner, &swarm_node.id, "active")?;
+        }
+    }
+
+    println!("All nodes run docker {}.", expected_version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, recording_config};
+    use std::fs;
+
+    const CLUSTER_FILE: &str = "
+version: 1
+nodes:
+  - ip: 10.0.0.2
+    username: deploy
+    role: manager
+  - ip: 10.0.0.3
+    username: deploy
+";
+
+    fn remove(runner: &RecordingRunner, ip: &str) -> (Result<(), String>, ClusterState) {
+        runner.respond(
+            "docker node inspect",
+            0,
+            "m1 10.0.0.2 web2 manager active ready 27.3.1\nw1 10.0.0.3 web3 worker active ready 27.3.1\n",
+            "",
+        );
+        let config = recording_config(CLUSTER_FILE, runner);
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+        for node_config in &config.nodes_configs {
+            state.record_joined_node(node_config);
+        }
+
+        let result = remove_node(&config, &mut state, ip);
+        (result, state)
+    }
+
+    #[test]
+    fn remove_worker_drains_it_before_it_leaves() {
+        let runner = RecordingRunner::new();
+        let (result, state) = remove(&runner, "10.0.0.3");
+        assert_eq!(result, Ok(()));
+
+        let commands: Vec<String> = runner
+            .recorded()
+            .into_iter()
+            .filter(|recorded| !recorded.command.contains("docker node inspect"))
+            .map(|recorded| recorded.command)
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "docker node update --availability drain w1",
+                "docker node ps w1 --format {{.CurrentState}}",
+                "docker swarm leave --force",
+                "docker node rm --force w1",
+            ]
+        );
+        assert_eq!(
+            runner.recorded_on(&Target::Node(String::from("10.0.0.3"))),
+            vec!["docker swarm leave --force"]
+        );
+        assert!(state.nodes.iter().all(|node_state| node_state.ip != "10.0.0.3"));
+    }
+
+    #[test]
+    fn remove_manager_demotes_it_first() {
+        let runner = RecordingRunner::new();
+        let (result, _) = remove(&runner, "10.0.0.2");
+        assert_eq!(result, Ok(()));
+
+        let local = runner.recorded_on(&Target::Local);
+        let demote = local.iter().position(|command| command == "docker node demote m1").unwrap();
+        let remove = local.iter().position(|command| command == "docker node rm --force m1").unwrap();
+        assert!(demote < remove);
+    }
+
+    #[test]
+    fn remove_unreachable_node_from_the_manager() {
+        let runner = RecordingRunner::new();
+        runner.set_unreachable("10.0.0.3");
+        let (result, state) = remove(&runner, "10.0.0.3");
+
+        assert_eq!(result, Ok(()));
+        assert!(runner.recorded_on(&Target::Local).contains(&String::from("docker node rm --force w1")));
+        assert_eq!(state.nodes.len(), 1);
+    }
+
+    #[test]
+    fn remove_node_stops_when_the_drain_fails() {
+        let runner = RecordingRunner::new();
+        runner.respond("docker node update", 1, "", "node w1 not found");
+        let (result, state) = remove(&runner, "10.0.0.3");
+
+        assert!(result.is_err());
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.3"))).is_empty());
+        assert_eq!(state.nodes.len(), 2);
+    }
+
+    #[test]
+    fn remove_unknown_node() {
+        let runner = RecordingRunner::new();
+        let (result, _) = remove(&runner, "10.0.0.9");
+
+        assert_eq!(result, Err(String::from("10.0.0.9 is not part of the cluster")));
+        assert!(runner.recorded().is_empty());
+    }
+
+    #[test]
+    fn upgrade_engine_fails_when_the_manager_is_outdated() {
+        let runner = RecordingRunner::new();
+        runner.respond(
+            "docker node inspect",
+            0,
+            "m1 10.0.0.2 web2 manager active ready 27.3.1\nw1 10.0.0.3 web3 worker active ready 26.1.4\n",
+            "",
+        );
+        runner.respond_on(Target::Local, "docker version --format", 0, "26.1.4\n", "");
+        let config = recording_config(&format!("{}docker_version: \"27.3\"\n", CLUSTER_FILE), &runner);
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+        for node_config in &config.nodes_configs {
+            state.record_joined_node(node_config);
+        }
+
+        let result = upgrade_engine(&config, &mut state);
+        assert_eq!(
+            result,
+            Err(String::from("The manager runs docker 26.1.4 but docker_version is 27.3, upgrade it first"))
+        );
+        // 10.0.0.3 doit être mise à jour mais n'est ni drainée ni touchée.
+        assert!(runner.recorded().iter().all(|recorded| !recorded.command.contains("docker node update")));
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.3"))).is_empty());
+    }
+
+    #[test]
+    fn add_node_declares_it_in_the_cluster_file() {
+        let runner = RecordingRunner::new();
+        runner.respond("cat /etc/os-release", 0, "ID=debian\nVERSION_ID=12\n", "");
+        runner.respond("docker version --format", 0, "27.3.1\n", "");
+        runner.respond(
+            "docker node inspect",
+            0,
+            "m1 10.0.0.2 web2 manager active ready 27.3.1\nw1 10.0.0.3 web3 worker active ready 27.3.1\nw2 10.0.0.4 web4 worker active ready 27.3.1\n",
+            "",
+        );
+        let cluster_file = std::env::temp_dir().join(format!("ClusterNoodle-add-node-{}.yml", std::process::id()));
+        fs::write(&cluster_file, CLUSTER_FILE).unwrap();
+        let mut config = recording_config(CLUSTER_FILE, &runner);
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+        state.advertise_addr = String::from("10.0.0.1");
+        state.worker_join_command = String::from("docker swarm join --token WORKER 10.0.0.1:2377");
+        for node_config in &config.nodes_configs {
+            state.record_joined_node(node_config);
+        }
+
+        let new_node = NewNode {
+            ip: String::from("10.0.0.4"),
+            username: String::from("admin"),
+            role: NodeRole::Worker,
+            port: Some(2222),
+            password: None,
+            sudo_password: Some(String::from("hunter2")),
+            identity_file: None,
+            ssh_agent: true,
+        };
+        let result = add_node(&mut config, &mut state, &cluster_file.to_string_lossy(), &new_node);
+        let contents = fs::read_to_string(&cluster_file).unwrap();
+        fs::remove_file(&cluster_file).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert!(state.nodes.iter().any(|node_state| node_state.ip == "10.0.0.4"));
+        let declared: ClusterConfig = serde_yaml::from_str(&contents).unwrap();
+        let node_config = declared.nodes_configs.iter().find(|node_config| node_config.ip == "10.0.0.4").unwrap();
+        assert_eq!(node_config.username, "admin");
+        assert_eq!(node_config.port, 2222);
+        assert_eq!(node_config.sudo_password.as_deref(), Some("hunter2"));
+        assert!(node_config.ssh_agent);
+    }
+
+    #[test]
+    fn upgrade_engine_drains_outdated_nodes_one_at_a_time() {
+        let runner = RecordingRunner::new();
+        runner.respond(
+            "docker node inspect",
+            0,
+            "m1 10.0.0.2 web2 manager active ready 27.3.1\nw1 10.0.0.3 web3 worker active ready 26.1.4\n",
+            "",
+        );
+        runner.respond("docker version --format", 0, "27.3.1\n", "");
+        runner.respond("cat /etc/os-release", 0, "ID=debian\nVERSION_ID=12\n", "");
+        let config = recording_config(&format!("{}docker_version: \"27.3\"\n", CLUSTER_FILE), &runner);
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+        for node_config in &config.nodes_configs {
+            state.record_joined_node(node_config);
+        }
+
+        assert_eq!(upgrade_engine(&config, &mut state), Ok(()));
+        let updates: Vec<String> = runner
+            .recorded_on(&Target::Local)
+            .into_iter()
+            .filter(|command| command.starts_with("docker node update"))
+            .collect();
+        assert_eq!(
+            updates,
+            vec!["docker node update --availability drain w1", "docker node update --availability active w1"]
+        );
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.2"))).is_empty());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::ClusterConfig;
+use crate::docker::cluster::STACK_NAME;
+use crate::services::services::project_image;
+use crate::utils::envVariables::EnvVariables;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Durée maximale d'attente du démarrage du registre après le déploiement de la stack.
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Construit l'image du projet sur le manager avec le Dockerfile généré pour le serveur.
+// Ne fait rien si aucun dossier de projet n'est déclaré.
+pub fn build_project_image(config: &ClusterConfig) -> Result<(), String> {
+    let Some(image) = project_image(config) else {
+        return Ok(());
+    };
+    let (Some(server), Some(project_folder_path)) = (&config.services.server, &config.project_folder_path) else {
+        return Ok(());
+    };
+
+    let env = EnvVariables {};
+    let dockerfile_path = env.get_project_dockerfile_path();
+    let dockerfile = server.project_dockerfile();
+    if config.dry_run {
+        println!("[dry-run] {} :", dockerfile_path);
+        println!("{}", dockerfile);
+    } else {
+        fs::write(&dockerfile_path, dockerfile)
+            .map_err(|e| format!("Failed to write {} : {}", dockerfile_path, e))?;
+    }
+
+    println!("Building {} from {}...", image, project_folder_path);
+    let mut cmd = Command::new("docker");
+    cmd.arg("build").arg("-t").arg(&image).arg("-f").arg(&dockerfile_path);
+    if let Some(config_path) = server.config_path()
+        && let Some(config_folder) = Path::new(&config_path).parent()
+    {
+        cmd.arg("--build-context").arg(format!("config={}", config_folder.display()));
+    }
+    cmd.arg(project_folder_path);
+
+    match config.runner.run(cmd, Duration::from_secs(1800)) {
+        Ok(outcome) if outcome.success() => Ok(()),
+        Ok(outcome) => Err(format!("Failed to build {}. Exit code : {:?}", image, outcome.exit_code)),
+        Err(e) => Err(format!("Failed to build {} : {}", image, e)),
+    }
+}
+
+// Pousse l'image du projet dans le registre du cluster, en attendant que celui-ci ait démarré.
+pub fn push_project_image(config: &ClusterConfig) -> Result<(), String> {
+    let Some(image) = project_image(config) else {
+        return Ok(());
+    };
+
+    println!("Pushing {} to the cluster registry...", image);
+    let started_at = Instant::now();
+    loop {
+        let mut cmd = Command::new("docker");
+        cmd.arg("push").arg(&image);
+        let outcome = config
+            .runner
+            .output(cmd, Duration::from_secs(600))
+            .map_err(|e| format!("Failed to push {} : {}", image, e))?;
+        if outcome.success() {
+            return Ok(());
+        }
+        if started_at.elapsed() >= REGISTRY_TIMEOUT {
+            return Err(format!("Failed to push {} : {}", image, outcome.stderr.trim()));
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+}
+
+// Redéploie le service du serveur pour qu'il utilise l'image qui vient d'être poussée : son
+// tag ne change pas, swarm ne le ferait pas de lui-même.
+pub fn update_project_service(config: &ClusterConfig) -> Result<(), String> {
+    let (Some(image), Some(server)) = (project_image(config), &config.services.server) else {
+        return Ok(());
+    };
+
+    let mut cmd = Command::new("docker");
+    cmd.arg("service")
+        .arg("update")
+        .arg("--force")
+        .arg("--detach")
+        .arg("--image")
+        .arg(&image)
+        .arg(format!("{}_{}", STACK_NAME, server.service_name()));
+    match config.runner.output(cmd, Duration::from_secs(120)) {
+        Ok(outcome) if outcome.success() => Ok(()),
+        Ok(outcome) => Err(format!("Failed to update the {} service : {}", server.service_name(), outcome.stderr.trim())),
+        Err(e) => Err(format!("Failed to update the {} service : {}", server.service_name(), e)),
+    }
+}
@@ -0,0 +1,385 @@
+use crate::ClusterConfig;
+use crate::config::config::NodeConfig;
+use crate::config::state::{self, ClusterState};
+use crate::docker::{cluster, registry};
+use crate::services::services::{create_docker_file, print_docker_file, render_docker_file};
+use crate::utils::identity;
+use crate::utils::parallel::NodeResult;
+use crate::utils::ssh;
+use crate::utils::sync;
+
+// Étapes du démarrage d'un cluster, dans leur ordre d'exécution. Chaque étape peut être
+// rejouée sans risque, ce qui permet de reprendre un démarrage interrompu.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    GenerateCompose,
+    InitCluster,
+    SshKeys,
+    InstallDocker,
+    JoinCluster,
+    PullImages,
+    SyncProject,
+    BuildImage,
+    Deploy,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 9] = [
+        Stage::GenerateCompose,
+        Stage::InitCluster,
+        Stage::SshKeys,
+        Stage::InstallDocker,
+        Stage::JoinCluster,
+        Stage::PullImages,
+        Stage::SyncProject,
+        Stage::BuildImage,
+        Stage::Deploy,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::GenerateCompose => "generate_compose",
+            Stage::InitCluster => "init_cluster",
+            Stage::SshKeys => "ssh_keys",
+            Stage::InstallDocker => "install_docker",
+            Stage::JoinCluster => "join_cluster",
+            Stage::PullImages => "pull_images",
+            Stage::SyncProject => "sync_project",
+            Stage::BuildImage => "build_image",
+            Stage::Deploy => "deploy",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Stage::GenerateCompose => "Generating docker-compose file...",
+            Stage::InitCluster => "Intializing cluster...",
+            Stage::SshKeys => "Generating ssh keys...",
+            Stage::InstallDocker => "Installating docker on target servers...",
+            Stage::JoinCluster => "Target servers are joining the cluster...",
+            Stage::PullImages => "Pulling docker images... This may take a while.",
+            Stage::SyncProject => "Copying the project to the nodes...",
+            Stage::BuildImage => "Building the project image...",
+            Stage::Deploy => "Deploying services to the cluster...",
+        }
+    }
+}
+
+pub struct StartOptions {
+    // Faux si l'utilisateur fournit son propre docker-compose ou ne veut pas le regénérer.
+    pub generate_compose: bool,
+}
+
+// Vrai tant que toutes les étapes du démarrage ne sont pas terminées. Elles s'exécutent
+// dans l'ordre, le démarrage est donc terminé avec la dernière, y compris pour un état
+// enregistré avant l'ajout d'une étape.
+pub fn is_start_incomplete(state: &ClusterState) -> bool {
+    !state.is_stage_completed(Stage::Deploy.name())
+}
+
+// Exécute les étapes du démarrage non encore terminées, en enregistrant l'avancement
+// après chacune d'entre elles.
+pub fn run_start(
+    config: &mut ClusterConfig,
+    state: &mut ClusterState,
+    options: &StartOptions,
+) -> Result<(), String> {
+    for stage in Stage::ALL {
+        if state.is_stage_completed(stage.name()) {
+            println!("Skipping stage {} (already completed)", stage.name());
+            continue;
+        }
+
+        println!("{}", stage.description());
+        let failed_nodes = run_stage(stage, config, state, options)?;
+
+        if failed_nodes.is_empty() {
+            state.complete_stage(stage.name());
+        }
+        state.save()?;
+
+        if !failed_nodes.is_empty() {
+            return Err(format!(
+                "Stage {} failed on {}. Fix the issue then run `start --resume` to continue.",
+                stage.name(),
+                failed_nodes.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Exécute une étape et renvoie les nodes sur lesquelles elle a échoué.
+fn run_stage(
+    stage: Stage,
+    config: &mut ClusterConfig,
+    state: &mut ClusterState,
+    options: &StartOptions,
+) -> Result<Vec<String>, String> {
+    match stage {
+        Stage::GenerateCompose => {
+            // L'étape n'est enregistrée comme terminée que si le fichier a bien été écrit.
+            if options.generate_compose {
+                let contents = render_docker_file(config)?;
+                if config.dry_run {
+                    print_docker_file(&config.compose_file_path, &contents);
+                } else {
+                    create_docker_file(&config.compose_file_path, &contents)
+                        .map_err(|e| format!("Failed to write {} : {}", config.compose_file_path, e))?;
+                }
+            }
+            state.docker_images = config.docker_images.clone();
+            state.services = config.services.clone();
+            Ok(vec![])
+        }
+        Stage::InitCluster => {
+            config.init_cluster();
+            if config.manager_join_command.is_empty() || config.worker_join_command.is_empty() {
+                return Err(String::from("Failed to initialize the swarm"));
+            }
+            state.manager_join_command = config.manager_join_command.clone();
+            state.worker_join_command = config.worker_join_command.clone();
+            Ok(vec![])
+        }
+        Stage::SshKeys => {
+            if !identity::check_existing_identity() {
+                identity::generate_identity(config.runner.as_ref());
+            }
+            let nodes = pending_nodes(stage, config, state);
+            let results = ssh::copy_ssh_key_to_machines(config, &nodes);
+            Ok(record_node_results(stage, state, &results))
+        }
+        Stage::InstallDocker => {
+            config.check_local_engine_version()?;
+            config.check_local_builder()?;
+            let nodes = pending_nodes(stage, config, state);
+            let (results, facts) = config.install_docker(&nodes);
+            state.node_facts.extend(facts);
+            Ok(record_node_results(stage, state, &results))
+        }
+        Stage::JoinCluster => {
+            let nodes = pending_nodes(stage, config, state);
+            let results = config.join_cluster(&nodes);
+            for node_config in &nodes {
+                if results.iter().any(|result| result.ip == node_config.ip && result.result.is_ok()) {
+                    state.record_joined_node(node_config);
+                }
+            }
+            state.set_swarm_nodes(&cluster::fetch_swarm_nodes(config.runner.as_ref()));
+            Ok(record_node_results(stage, state, &results))
+        }
+        Stage::PullImages => {
+            let nodes = pending_nodes(stage, config, state);
+            let results = config.pull_docker_images(&nodes, &state.hostnames())?;
+            Ok(record_node_results(stage, state, &results))
+        }
+        Stage::SyncProject => {
+            if !config.sync_project {
+                return Ok(vec![]);
+            }
+            let nodes = pending_nodes(stage, config, state);
+            let results = sync::sync_project(config, &nodes);
+            Ok(record_node_results(stage, state, &results))
+        }
+        Stage::BuildImage => {
+            registry::build_project_image(config)?;
+            Ok(vec![])
+        }
+        Stage::Deploy => {
+            if !cluster::deploy_services(config, false) {
+                return Err(String::from("Failed to deploy the services"));
+            }
+            // Le registre démarre avec la stack, les tâches du serveur attendent son image.
+            registry::push_project_image(config)?;
+            registry::update_project_service(config)?;
+            state.compose_hash = state::hash_file(&config.compose_file_path);
+            Ok(vec![])
+        }
+    }
+}
+
+// Nodes n'ayant pas encore terminé l'étape.
+fn pending_nodes<'a>(stage: Stage, config: &'a ClusterConfig, state: &ClusterState) -> Vec<&'a NodeConfig> {
+    config
+        .nodes_configs
+        .iter()
+        .filter(|node_config| {
+            let completed = state.is_node_completed(stage.name(), &node_config.ip);
+            if completed {
+                println!("[{}] Skipping {} (already completed)", node_config.ip, stage.name());
+            }
+            !completed
+        })
+        .collect()
+}
+
+fn record_node_results(stage: Stage, state: &mut ClusterState, results: &[NodeResult]) -> Vec<String> {
+    let mut failed_nodes = vec![];
+    for result in results {
+        match result.result {
+            Ok(_) => state.complete_node(stage.name(), &result.ip),
+            Err(_) => failed_nodes.push(result.ip.clone()),
+        }
+    }
+    failed_nodes
+}
+
+// Lors d'une reprise, on restaure dans la configuration ce que les étapes déjà terminées
+// avaient produit.
+pub fn restore_from_state(config: &mut ClusterConfig, state: &ClusterState) {
+    config.sync_project |= state.sync_project;
+    if state.is_stage_completed(Stage::GenerateCompose.name()) {
+        config.docker_images = state.docker_images.clone();
+    }
+    if state.is_stage_completed(Stage::InitCluster.name()) {
+        config.ip_adress = Some(state.advertise_addr.clone());
+        config.manager_join_command = state.manager_join_command.clone();
+        config.worker_join_command = state.worker_join_command.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, position, recording_config};
+
+    const CLUSTER_FILE: &str = "
+version: 1
+ip_adress: 10.0.0.1
+nodes:
+  - ip: 10.0.0.2
+    username: deploy
+    role: manager
+    ssh_agent: true
+  - ip: 10.0.0.3
+    username: deploy
+    ssh_agent: true
+";
+
+    fn recording_runner() -> RecordingRunner {
+        let runner = RecordingRunner::new();
+        runner.respond("join-token", 0, "SWMTKN-1-test", "");
+        runner.respond("cat /etc/os-release", 0, "ID=debian\nVERSION_ID=12\n", "");
+        runner.respond("docker version --format", 0, "27.3.1", "");
+        runner.respond("id -nG", 0, "deploy docker", "");
+        runner
+    }
+
+    fn start(runner: &RecordingRunner) -> (Result<(), String>, ClusterState) {
+        let mut config = recording_config(CLUSTER_FILE, runner);
+        config.docker_images = vec![String::from("nginx:1.27")];
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+        let options = StartOptions { generate_compose: false };
+
+        let result = run_start(&mut config, &mut state, &options);
+        (result, state)
+    }
+
+    #[test]
+    fn start_runs_every_stage_in_order() {
+        let runner = recording_runner();
+        let (result, state) = start(&runner);
+        assert_eq!(result, Ok(()));
+
+        let local = runner.recorded_on(&Target::Local);
+        assert!(position(&local, "docker swarm init --advertise-addr 10.0.0.1") < position(&local, "join-token -q manager"));
+        assert!(position(&local, "join-token -q worker") < position(&local, "docker pull nginx:1.27"));
+        assert!(position(&local, "docker pull nginx:1.27") < position(&local, "docker stack deploy"));
+
+        for ip in ["10.0.0.2", "10.0.0.3"] {
+            let remote = runner.recorded_on(&Target::Node(String::from(ip)));
+            assert!(position(&remote, "sudo -n true") < position(&remote, "cat /etc/os-release"));
+            assert!(position(&remote, "cat /etc/os-release") < position(&remote, "docker swarm join --token SWMTKN-1-test 10.0.0.1:2377"));
+            assert!(position(&remote, "docker swarm join") < position(&remote, "docker pull -q 'nginx:1.27'"));
+        }
+
+        for stage in Stage::ALL {
+            assert!(state.is_stage_completed(stage.name()), "{} not completed", stage.name());
+        }
+        assert_eq!(state.nodes.len(), 2);
+    }
+
+    #[test]
+    fn start_stops_at_the_failed_stage() {
+        let runner = recording_runner();
+        runner.respond_on(Target::Node(String::from("10.0.0.3")), "docker swarm join", 1, "", "connection refused");
+        let (result, state) = start(&runner);
+
+        let error = result.err().unwrap();
+        assert!(error.contains("join_cluster failed on 10.0.0.3"), "{}", error);
+        assert!(state.is_node_completed(Stage::JoinCluster.name(), "10.0.0.2"));
+        assert!(!state.is_node_completed(Stage::JoinCluster.name(), "10.0.0.3"));
+        assert!(!state.is_stage_completed(Stage::JoinCluster.name()));
+        assert!(is_start_incomplete(&state));
+
+        // Rien n'est déployé tant que toutes les nodes n'ont pas rejoint le cluster.
+        let local = runner.recorded_on(&Target::Local);
+        assert!(local.iter().all(|command| !command.contains("docker stack deploy")));
+    }
+
+    #[test]
+    fn start_checks_buildx_before_joining_nodes() {
+        let runner = recording_runner();
+        runner.respond("docker buildx version", 1, "", "docker: 'buildx' is not a docker command.");
+        let mut config = recording_config(
+            &format!("{}services:\n  server: nginx\nproject_folder_path: /srv/project\n", CLUSTER_FILE),
+            &runner,
+        );
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+
+        let result = run_start(&mut config, &mut state, &StartOptions { generate_compose: false });
+
+        let error = result.err().unwrap();
+        assert!(error.contains("docker buildx"), "{}", error);
+        let recorded = runner.recorded();
+        assert!(recorded.iter().all(|recorded| recorded.target == Target::Local));
+    }
+
+    #[test]
+    fn start_records_unreachable_nodes() {
+        let runner = recording_runner();
+        runner.set_unreachable("10.0.0.2");
+        let (result, state) = start(&runner);
+
+        let error = result.err().unwrap();
+        // Les nodes utilisant l'agent SSH n'ont pas de clé à installer, la première
+        // connexion a lieu à l'installation de docker.
+        assert!(error.contains("install_docker failed on 10.0.0.2"), "{}", error);
+        assert!(state.is_node_completed(Stage::InstallDocker.name(), "10.0.0.3"));
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.2"))).is_empty());
+    }
+
+    #[test]
+    fn start_writes_the_compose_file() {
+        let runner = recording_runner();
+        let mut config = recording_config(CLUSTER_FILE, &runner);
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+
+        let result = run_start(&mut config, &mut state, &StartOptions { generate_compose: true });
+        let written = std::fs::read_to_string(&config.compose_file_path);
+        let _ = std::fs::remove_file(&config.compose_file_path);
+
+        assert_eq!(result, Ok(()));
+        assert!(written.unwrap().contains("services"));
+        assert!(state.is_stage_completed(Stage::GenerateCompose.name()));
+    }
+
+    #[test]
+    fn start_stops_when_the_compose_file_cannot_be_written() {
+        let runner = recording_runner();
+        let mut config = recording_config(CLUSTER_FILE, &runner);
+        config.compose_file_path = String::from("/nonexistent/ClusterNoodle/docker-compose.yml");
+        let mut state = ClusterState::from_config(&config, cluster::STACK_NAME);
+        state.dry_run = true;
+
+        let result = run_start(&mut config, &mut state, &StartOptions { generate_compose: true });
+
+        assert!(result.is_err());
+        assert!(!state.is_stage_completed(Stage::GenerateCompose.name()));
+        assert!(runner.recorded().is_empty());
+    }
+}
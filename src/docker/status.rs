@@ -0,0 +1,90 @@
+use crate::ClusterConfig;
+use crate::config::state::ClusterState;
+use crate::docker::cluster::{self, SwarmNode};
+use crate::services::services::read_published_ports;
+use crate::utils::platform::engine_version_matches;
+
+// Affiche l'état du cluster : appartenance des nodes au swarm, services de la stack
+// et adresses auxquelles les services publiés sont joignables.
+pub fn print_status(config: &ClusterConfig, state: Option<&ClusterState>) {
+    let swarm_nodes = cluster::fetch_swarm_nodes(config.runner.as_ref());
+    let advertise_addr = state
+        .map(|state| state.advertise_addr.clone())
+        .or_else(|| config.ip_adress.clone())
+        .unwrap_or_default();
+
+    println!("Nodes :");
+    println!(
+        "  {:<16} {:<20} {:<8} {:<12} {:<10} ENGINE",
+        "IP", "HOSTNAME", "ROLE", "AVAILABILITY", "STATE"
+    );
+    let expected_version = config.docker_version.as_deref();
+    if !advertise_addr.is_empty() {
+        print_node(
+            &advertise_addr,
+            swarm_nodes.iter().find(|node| node.addr == advertise_addr),
+            expected_version,
+        );
+    }
+    for node_config in &config.nodes_configs {
+        print_node(
+            &node_config.ip,
+            swarm_nodes.iter().find(|node| node.addr == node_config.ip),
+            expected_version,
+        );
+    }
+
+    // Sans version imposée, on signale tout de même un swarm aux versions mélangées.
+    let mut engine_versions: Vec<&str> = swarm_nodes.iter().map(|node| node.engine_version.as_str()).collect();
+    engine_versions.sort();
+    engine_versions.dedup();
+    if expected_version.is_none() && engine_versions.len() > 1 {
+        println!("  Warning: mixed docker engine versions ({})", engine_versions.join(", "));
+    }
+
+    println!();
+    println!("Services :");
+    match cluster::fetch_stack_services(config.runner.as_ref()) {
+        Ok(services) if services.is_empty() => println!("  No service deployed in stack {}", cluster::STACK_NAME),
+        Ok(services) => {
+            println!("  {:<28} {:<10} PORTS", "NAME", "REPLICAS");
+            for service in services {
+                let replicas = format!("{}/{}", service.running_replicas, service.desired_replicas);
+                println!("  {:<28} {:<10} {}", service.name, replicas, service.ports);
+            }
+        }
+        Err(e) => println!("  {}", e),
+    }
+
+    println!();
+    println!("URLs :");
+    match read_published_ports(&config.compose_file_path) {
+        Ok(ports) if ports.is_empty() => println!("  No published port"),
+        Ok(ports) => {
+            // Le routing mesh de swarm rend les ports publiés joignables depuis n'importe quelle node.
+            for published in ports {
+                let scheme = if published.port == 443 { "https" } else { "http" };
+                println!("  {:<28} {}://{}:{}", published.service, scheme, advertise_addr, published.port);
+            }
+        }
+        Err(e) => println!("  {}", e),
+    }
+}
+
+fn print_node(ip: &str, swarm_node: Option<&SwarmNode>, expected_version: Option<&str>) {
+    match swarm_node {
+        Some(node) => {
+            let drift = match expected_version {
+                Some(expected_version) if !engine_version_matches(&node.engine_version, expected_version) => {
+                    format!("  <- drift, expected {}", expected_version)
+                }
+                _ => String::new(),
+            };
+            println!(
+                "  {:<16} {:<20} {:<8} {:<12} {:<10} {}{}",
+                ip, node.hostname, node.role, node.availability, node.state, node.engine_version, drift
+            )
+        }
+        None => println!("  {:<16} not part of the swarm", ip),
+    }
+}
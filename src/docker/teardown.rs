@@ -0,0 +1,165 @@
+use crate::ClusterConfig;
+use crate::config::config::NodeConfig;
+use crate::docker::cluster;
+use crate::services::services::Services;
+use crate::utils::ssh;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+// Durée maximale d'attente de l'arrêt des tâches de la stack.
+const STACK_REMOVAL_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct StopOptions {
+    // Supprime les volumes nommés de la stack, ceux de la base de données après confirmation.
+    pub remove_volumes: bool,
+    // Désinstalle docker des nodes une fois qu'elles ont quitté le swarm.
+    pub purge_docker: bool,
+}
+
+// Arrêt complet du cluster, étape par étape : suppression de la stack, attente de l'arrêt
+// des tâches, volumes, départ des nodes, purge de docker et retrait de la clé SSH.
+// `services` sont les services déployés, pour identifier le volume de la base de données.
+pub fn run_stop(config: &ClusterConfig, services: &Services, options: &StopOptions) {
+    let runner = config.runner.as_ref();
+    let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+
+    println!("Removing stack {}...", cluster::STACK_NAME);
+    match cluster::remove_stack(runner) {
+        Ok(()) => {
+            println!("Waiting for the services to stop...");
+            if let Err(e) = cluster::wait_for_stack_removal(runner, STACK_REMOVAL_TIMEOUT) {
+                eprintln!("Error: {}", e);
+            }
+            // Les volumes d'une node ne peuvent être supprimés qu'une fois ses conteneurs arrêtés.
+            config.wait_for_stack_containers(&nodes, STACK_REMOVAL_TIMEOUT);
+        }
+        Err(e) => eprintln!("Error: {}", e),
+    }
+
+    if options.remove_volumes {
+        let kept_volumes = database_volumes_to_keep(config, services);
+        println!("Removing volumes...");
+        if let Err(e) = cluster::remove_local_volumes(runner, &kept_volumes) {
+            eprintln!("Error: {}", e);
+        }
+        config.remove_volumes(&nodes, &kept_volumes);
+    }
+
+    println!("Stopping the cluster...");
+    config.leave_cluster(&nodes);
+    cluster::destroy_cluster(runner);
+
+    if options.purge_docker {
+        println!("Purging docker from target servers...");
+        config.purge_docker(&nodes);
+    }
+
+    // En dernier : les nodes authentifiées par cette clé ne seront plus joignables ensuite.
+    println!("Removing ssh keys from target servers...");
+    ssh::remove_ssh_key_from_machines(config, &nodes);
+}
+
+// Le volume de la base de données n'est supprimé qu'après une confirmation explicite.
+fn database_volumes_to_keep(config: &ClusterConfig, services: &Services) -> Vec<String> {
+    let Some(database) = &services.database else {
+        return vec![];
+    };
+
+    let volume = format!("{}_{}", cluster::STACK_NAME, database.data_volume());
+    if config.dry_run {
+        println!("[dry-run] confirmation required to remove the database volume {}", volume);
+        return vec![];
+    }
+
+    print!(
+        "The volume {} contains the database data and will be permanently deleted. Type its name to confirm : ",
+        volume
+    );
+    let _ = io::stdout().flush();
+
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    if answer.trim() == volume {
+        vec![]
+    } else {
+        println!("Keeping {}", volume);
+        vec![volume]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, position, recording_config};
+
+    const CLUSTER_FILE: &str = "
+version: 1
+nodes:
+  - ip: 10.0.0.2
+    username: deploy
+    ssh_agent: true
+  - ip: 10.0.0.3
+    username: deploy
+    ssh_agent: true
+";
+
+    fn stop(runner: &RecordingRunner, options: &StopOptions) {
+        let config = recording_config(CLUSTER_FILE, runner);
+        run_stop(&config, &Services::default(), options);
+    }
+
+    #[test]
+    fn stop_tears_down_in_order() {
+        let runner = RecordingRunner::new();
+        runner.respond("cat /etc/os-release", 0, "ID=debian\nVERSION_ID=12\n", "");
+        stop(&runner, &StopOptions { remove_volumes: true, purge_docker: true });
+
+        let local = runner.recorded_on(&Target::Local);
+        assert!(position(&local, "docker stack rm") < position(&local, "docker service ls -q"));
+        assert!(position(&local, "docker service ls -q") < position(&local, "xargs -r docker volume rm"));
+        assert_eq!(local.last().unwrap(), "docker swarm leave --force");
+
+        for ip in ["10.0.0.2", "10.0.0.3"] {
+            let remote = runner.recorded_on(&Target::Node(String::from(ip)));
+            assert!(position(&remote, "docker ps -q --filter label=com.docker.stack.namespace=server") < position(&remote, "xargs -r docker volume rm"));
+            assert!(position(&remote, "xargs -r docker volume rm") < position(&remote, "docker swarm leave --force"));
+            assert!(position(&remote, "docker swarm leave --force") < position(&remote, "rm -rf /var/lib/docker"));
+        }
+
+        // Les volumes des nodes ne sont supprimés qu'une fois la stack arrêtée.
+        let recorded = runner.recorded();
+        let stack_stopped = recorded
+            .iter()
+            .position(|recorded| recorded.command.contains("docker service ls -q"))
+            .unwrap();
+        let first_node_command = recorded
+            .iter()
+            .position(|recorded| recorded.target != Target::Local)
+            .unwrap();
+        assert!(stack_stopped < first_node_command);
+    }
+
+    #[test]
+    fn stop_keeps_volumes_and_docker_by_default() {
+        let runner = RecordingRunner::new();
+        stop(&runner, &StopOptions { remove_volumes: false, purge_docker: false });
+
+        let recorded = runner.recorded();
+        assert!(recorded.iter().all(|recorded| !recorded.command.contains("docker volume rm")));
+        assert!(recorded.iter().all(|recorded| !recorded.command.contains("/var/lib/docker")));
+    }
+
+    #[test]
+    fn stop_goes_on_when_a_node_fails() {
+        let runner = RecordingRunner::new();
+        runner.set_unreachable("10.0.0.2");
+        runner.respond("docker stack rm", 1, "", "nothing found in stack");
+        stop(&runner, &StopOptions { remove_volumes: false, purge_docker: false });
+
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.2"))).is_empty());
+        let remote = runner.recorded_on(&Target::Node(String::from("10.0.0.3")));
+        assert_eq!(remote, vec!["docker swarm leave --force"]);
+        let local = runner.recorded_on(&Target::Local);
+        assert_eq!(local.last().unwrap(), "docker swarm leave --force");
+    }
+}
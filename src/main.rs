@@ -1,6 +1,13 @@
 #![allow(non_snake_case)]
 mod docker {
+    pub mod apply;
+    pub mod bundle;
     pub mod cluster;
+    pub mod nodes;
+    pub mod registry;
+    pub mod stages;
+    pub mod status;
+    pub mod teardown;
 }
 
 mod utils {
@@ -8,7 +15,12 @@ mod utils {
     pub mod envParsing;
     pub mod envVariables;
     pub mod fs;
+    pub mod identity;
+    pub mod parallel;
+    pub mod platform;
+    pub mod runner;
     pub mod ssh;
+    pub mod sync;
 }
 
 mod services {
@@ -23,18 +35,27 @@ mod services {
 
 mod config {
     pub mod config;
+    pub mod state;
 }
 
 use crate::config::config::{
-    ClusterConfig, build_cluster_nodes_objects, check_conf_file_exists, init_app_config_folder,
+    ClusterConfig, NodeRole, check_conf_file_exists, init_app_config_folder, load_cluster_config,
 };
-use crate::services::services::{DatabaseType, ServerType, generate_docker_file};
+use crate::config::state::ClusterState;
+use crate::services::services::{DatabaseType, ServerType};
 use clap::{Args, Parser, Subcommand};
+use docker::apply;
+use docker::bundle;
 use docker::cluster;
+use docker::nodes;
+use docker::stages;
+use docker::status;
+use docker::teardown;
 use std::path::PathBuf;
+use std::sync::Arc;
 use utils::envVariables::EnvVariables;
+use utils::runner::DryRunRunner;
 use utils::fs;
-use utils::ssh;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -47,6 +68,14 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
 
+    /// Maximum number of nodes processed at the same time
+    #[arg(long, global = true)]
+    parallelism: Option<usize>,
+
+    /// Print every command and generated file instead of running or writing them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -77,8 +106,90 @@ enum Commands {
 
         #[arg(long)]
         ssl_certificate_path_crt: Option<String>,
+
+        /// Resume an interrupted start from its last completed stage
+        #[arg(long)]
+        resume: bool,
+
+        /// Start from an offline bundle created with `bundle`, without internet access
+        #[arg(long, value_name = "FILE")]
+        bundle: Option<PathBuf>,
+
+        /// Copy the project folder and the server configuration to every node instead of
+        /// building an image from them
+        #[arg(long)]
+        sync_project: bool,
     },
-    Stop {},
+    Stop {
+        /// Also remove the named volumes of the stack (the database volume requires a confirmation)
+        #[arg(long)]
+        remove_volumes: bool,
+
+        /// Uninstall docker from the nodes once they left the swarm
+        #[arg(long)]
+        purge_docker: bool,
+    },
+    /// Package the compose file, images, docker packages and config assets for an offline start
+    Bundle {
+        #[command(flatten)]
+        services: Services,
+
+        /// Bundle file to write
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// Docker image of a node distribution to bundle the docker packages for (debian:12, rockylinux:9...)
+        #[arg(long = "distro", value_name = "IMAGE")]
+        distros: Vec<String>,
+    },
+    /// Show the nodes, services and URLs of the running cluster
+    Status {},
+    /// Update the running cluster from the cluster file : join new nodes and redeploy changed services
+    Apply {},
+    /// Add a node to the running cluster
+    AddNode {
+        ip: String,
+        user: String,
+
+        #[arg(long, value_enum, default_value_t = NodeRole::Worker)]
+        role: NodeRole,
+
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// SSH password, used to install the ClusterNoodle key on the node
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Sudo password of the node if it differs from the SSH password
+        #[arg(long)]
+        sudo_password: Option<String>,
+
+        /// Private key to log into the node instead of ~/.ssh/cluster_noodle
+        #[arg(long, value_name = "FILE")]
+        identity_file: Option<String>,
+
+        /// Log into the node with the keys loaded in the SSH agent
+        #[arg(long)]
+        ssh_agent: bool,
+    },
+    /// Drain a node and remove it from the running cluster
+    RemoveNode { ip: String },
+    /// Upgrade the docker engine of the nodes to docker_version, one node at a time
+    UpgradeEngine {},
+    /// Manage the availability of a node
+    Node {
+        #[command(subcommand)]
+        command: NodeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum NodeCommands {
+    /// Move the tasks of a node elsewhere and stop scheduling on it (maintenance)
+    Drain { ip: String },
+    /// Schedule tasks on a drained node again
+    Activate { ip: String },
 }
 
 #[derive(Args)]
@@ -89,11 +200,12 @@ struct Services {
     #[arg(long, value_enum)]
     database: Option<DatabaseType>,
 
-    #[arg(long)]
-    traefik: bool,
+    // `--traefik` active le service, `--traefik=false` le désactive malgré le fichier de définition.
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    traefik: Option<bool>,
 
-    #[arg(long)]
-    dashboard: bool,
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    dashboard: Option<bool>,
 }
 
 fn main() {
@@ -102,10 +214,17 @@ fn main() {
 
     // Initialiser les ressources nécessaires pour l'application
     init_app_config_folder();
-    check_conf_file_exists();
 
     let cli = Cli::parse();
 
+    // Le fichier de définition du cluster peut être surchargé avec --config.
+    let env = EnvVariables {};
+    let conf_file_path = match &cli.config {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => env.get_conf_file_path(),
+    };
+    check_conf_file_exists(&conf_file_path);
+
     match &cli.command {
         Some(Commands::Start {
             docker_compose_file,
@@ -115,10 +234,29 @@ fn main() {
             project_entry_file_path,
             ssl_certificate_path_key,
             ssl_certificate_path_crt,
-            no_rebuild_docker_compose_file
+            no_rebuild_docker_compose_file,
+            resume,
+            bundle,
+            sync_project,
         }) => {
-            let services_specified =
-                services.server.is_some() || services.database.is_some() || services.traefik;
+            // On récupère la configuration du cluster dans le fichier de définition,
+            // les options passées en ligne de commande sont prioritaires.
+            println!("Loading cluster definition from {}...", conf_file_path);
+            let mut config = load_config(&conf_file_path, &cli);
+            apply_cli_overrides(
+                &mut config,
+                services,
+                ip_adress,
+                project_folder_path,
+                project_entry_file_path,
+                ssl_certificate_path_key,
+                ssl_certificate_path_crt,
+            );
+            config.sync_project |= *sync_project;
+
+            let services_specified = config.services.server.is_some()
+                || config.services.database.is_some()
+                || config.services.traefik;
 
             if docker_compose_file.is_some() && services_specified {
                 eprintln!(
@@ -127,113 +265,348 @@ fn main() {
                 std::process::exit(1);
             }
 
-            if docker_compose_file.is_none() && !services_specified {
+            // Le bundle fournit le fichier docker-compose généré à partir de ces services.
+            let compose_provided = docker_compose_file.is_some() || bundle.is_some();
+
+            if !compose_provided && !services_specified {
                 eprintln!(
                     "Error: You must specify either `--docker-compose-file` or at least one service."
                 );
                 std::process::exit(1);
             }
 
-            // Vérifie s'il y a déjà un cluster en exécution
-            if cluster::check_existing_cluster() {
-                println!(
-                    "A cluster is already running. You have to stop the running cluster before starting a new one."
-                );
-                return;
-            }
+            let mut state = if *resume {
+                // On reprend un démarrage interrompu là où il s'était arrêté.
+                match ClusterState::load() {
+                    Ok(Some(mut state)) if stages::is_start_incomplete(&state) => {
+                        stages::restore_from_state(&mut config, &state);
+                        state.dry_run = config.dry_run;
+                        state
+                    }
+                    Ok(Some(_)) => {
+                        println!("The cluster is already fully started, there is nothing to resume.");
+                        return;
+                    }
+                    Ok(None) => {
+                        eprintln!("Error: no interrupted start to resume, run `start` without --resume.");
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                // Vérifie s'il y a déjà un cluster en exécution
+                if cluster::check_existing_cluster(config.runner.as_ref()) {
+                    if let Ok(Some(state)) = ClusterState::load()
+                        && stages::is_start_incomplete(&state)
+                    {
+                        println!(
+                            "A previous start did not complete. Run `start --resume` to continue it, or stop the cluster first."
+                        );
+                    } else {
+                        println!(
+                            "A cluster is already running. Run `apply` to update it, or stop it before starting a new one."
+                        );
+                    }
+                    return;
+                }
+
+                // Fetch and set IP address before generating docker-compose file
+                println!("Fetching IP address...");
+                let ip_adress = config.ip_adress.clone();
+                config.fetch_and_set_ip_address(&ip_adress);
 
-            // On récupère la configuration des nodes du cluster dans le fichier
-            // conf.cluster_noodle.
-
-            println!("Getting servers from the conf file...");
-            let env = EnvVariables {};
-            let conf_file_path = env.get_conf_file_path();
-            let nodes_configs = build_cluster_nodes_objects(&conf_file_path);
-
-            let mut config = ClusterConfig {
-                nodes_number: 0,
-                nodes_configs: nodes_configs,
-                cluster_docker_command: String::from(""),
-                services: crate::services::services::Services {
-                    server: services.server.clone(),
-                    database: services.database.clone(),
-                    traefik: services.traefik.clone(),
-                    dashboard: services.dashboard.clone()
-                },
-                ip_adress: ip_adress.clone(),
-                project_folder_path: project_folder_path.clone(),
-                project_entry_file_path: project_entry_file_path.clone(),
-                ssl_certificate_path_key: ssl_certificate_path_key.clone(),
-                ssl_certificate_path_crt: ssl_certificate_path_crt.clone(),
-                docker_images: vec![]
+                ClusterState::from_config(&config, cluster::STACK_NAME)
             };
 
-            // Fetch and set IP address before generating docker-compose file
-            println!("Fetching IP address...");
-            config.fetch_and_set_ip_address(ip_adress);
+            if let Some(bundle_path) = bundle
+                && let Err(e) = bundle::open_bundle(&mut config, bundle_path)
+            {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
 
-            // On ne génère le fichier doccaptker_compose uniquement si l'utilisateur n'a pas renseigné
+            // On ne génère le fichier docker_compose uniquement si l'utilisateur n'a pas renseigné
             // le sien.
-            if !docker_compose_file.is_some() && !*no_rebuild_docker_compose_file  {
-                println!("Generating docker-compose file...");
-                if let Err(e) = generate_docker_file(&mut config) {
-                    eprintln!("Error generating docker-compose file: {}", e);
+            let options = stages::StartOptions {
+                generate_compose: !compose_provided && !*no_rebuild_docker_compose_file,
+            };
+
+            if let Err(e) = stages::run_start(&mut config, &mut state, &options) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Stop { remove_volumes, purge_docker }) => {
+            let mut config = load_config(&conf_file_path, &cli);
+
+            // Les nodes à arrêter sont celles enregistrées au démarrage du cluster.
+            let mut services = config.services.clone();
+            match ClusterState::load() {
+                Ok(Some(state)) => {
+                    config.nodes_configs = state.node_configs(&config);
+                    services = state.services.clone();
                 }
+                Ok(None) => println!("No cluster state found, using the nodes of {}", conf_file_path),
+                Err(e) => eprintln!("Error: {}", e),
             }
 
-            // Init le cluster
-            println!("Intializing cluster...");
-            config.init_cluster();
+            let options = teardown::StopOptions {
+                remove_volumes: *remove_volumes,
+                purge_docker: *purge_docker,
+            };
+            teardown::run_stop(&config, &services, &options);
 
-            // Création des clés et connexions en SSH aux nodes
-            println!("Generating ssh keys...");
-            if !ssh::check_existing_ssh_key() {
-                ssh::generate_ssh_key();
+            if config.dry_run {
+                println!("[dry-run] state file {} not removed", env.get_state_file_path());
+            } else if let Err(e) = ClusterState::remove() {
+                eprintln!("Error: {}", e);
             }
-            ssh::copy_ssh_key_to_machines(&config);
+        }
+        Some(Commands::Bundle { services, out, distros }) => {
+            let mut config = load_config(&conf_file_path, &cli);
+            apply_cli_overrides(&mut config, services, &None, &None, &None, &None, &None);
 
-            // Installation de docker sur chaque machine
+            if let Err(e) = bundle::create_bundle(&mut config, out, distros) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Apply {}) => {
+            let mut config = load_config(&conf_file_path, &cli);
 
-            println!("Installating docker on target servers...");
-            config.install_docker();
+            let mut state = load_running_state(&config);
 
-            // On fait rejoindre le cluster à chaque machine
-            println!("Target servers are joining the cluster...");
-            config.join_cluster();
+            if let Err(e) = apply::apply(&mut config, &mut state) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::AddNode {
+            ip,
+            user,
+            role,
+            port,
+            password,
+            sudo_password,
+            identity_file,
+            ssh_agent,
+        }) => {
+            let mut config = load_config(&conf_file_path, &cli);
+            let mut state = load_running_state(&config);
+
+            let new_node = nodes::NewNode {
+                ip: ip.clone(),
+                username: user.clone(),
+                role: *role,
+                port: *port,
+                password: password.clone(),
+                sudo_password: sudo_password.clone(),
+                identity_file: identity_file.clone(),
+                ssh_agent: *ssh_agent,
+            };
+            if let Err(e) = nodes::add_node(&mut config, &mut state, &conf_file_path, &new_node) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::RemoveNode { ip }) => {
+            let config = load_config(&conf_file_path, &cli);
+            let mut state = load_running_state(&config);
 
-            println!("Pulling docker images... This may take a while.");
-            config.pull_docker_images();
+            if let Err(e) = nodes::remove_node(&config, &mut state, ip) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::UpgradeEngine {}) => {
+            let config = load_config(&conf_file_path, &cli);
+            let mut state = load_running_state(&config);
 
-            // Déploiement des services docker
-            println!("Deploying services to the cluster...");
-            cluster::deploy_services();
+            if let Err(e) = nodes::upgrade_engine(&config, &mut state) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
-        Some(Commands::Stop {}) => {
-            let env = EnvVariables {};
-            let conf_file_path = env.get_conf_file_path();
-
-            let mut config = ClusterConfig {
-                ip_adress: Some(String::from("")),
-                nodes_number: 0,
-                nodes_configs: build_cluster_nodes_objects(&conf_file_path),
-                cluster_docker_command: String::from(""),
-                services: services::services::Services {
-                    database: None,
-                    server: None,
-                    traefik: false,
-                    dashboard: false
-                },
-                project_folder_path: Some(String::from("")),
-                project_entry_file_path: Some(String::from("")),
-                ssl_certificate_path_key: Some(String::from("")),
-                ssl_certificate_path_crt: Some(String::from("")),
-                docker_images: vec![]
+        Some(Commands::Node { command }) => {
+            let config = load_config(&conf_file_path, &cli);
+            let state = match ClusterState::load() {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    None
+                }
             };
 
-            println!("Stopping the cluster...");
-            config.leave_cluster();
-            cluster::destroy_cluster();
+            let result = match command {
+                NodeCommands::Drain { ip } => nodes::drain_node(&config, state.as_ref(), ip),
+                NodeCommands::Activate { ip } => nodes::activate_node(&config, state.as_ref(), ip),
+            };
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Status {}) => {
+            let config = load_config(&conf_file_path, &cli);
+
+            let state = match ClusterState::load() {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    None
+                }
+            };
+            status::print_status(&config, state.as_ref());
         }
         None => {}
     }
 }
+
+// Charge le fichier de définition et applique les options globales de la ligne de commande.
+fn load_config(conf_file_path: &str, cli: &Cli) -> ClusterConfig {
+    let mut config = match load_cluster_config(conf_file_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(parallelism) = cli.parallelism {
+        config.parallelism = parallelism;
+    }
+    if cli.dry_run {
+        config.dry_run = true;
+        config.runner = Arc::new(DryRunRunner::new(config.docker_version.as_deref()));
+    }
+    config
+}
+
+// État d'un cluster entièrement démarré, les commandes modifiant un cluster en cours
+// d'exécution en ont besoin.
+fn load_running_state(config: &ClusterConfig) -> ClusterState {
+    match ClusterState::load() {
+        Ok(Some(mut state)) if !stages::is_start_incomplete(&state) => {
+            state.dry_run = config.dry_run;
+            state
+        }
+        Ok(Some(_)) => {
+            eprintln!("Error: the cluster start did not complete, run `start --resume` first.");
+            std::process::exit(1);
+        }
+        Ok(None) => {
+            eprintln!("Error: no running cluster, run `start` first.");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Les options de la ligne de commande surchargent les champs du fichier de définition.
+fn apply_cli_overrides(
+    config: &mut ClusterConfig,
+    services: &Services,
+    ip_adress: &Option<String>,
+    project_folder_path: &Option<String>,
+    project_entry_file_path: &Option<String>,
+    ssl_certificate_path_key: &Option<String>,
+    ssl_certificate_path_crt: &Option<String>,
+) {
+    if services.server.is_some() {
+        config.services.server = services.server.clone();
+    }
+    if services.database.is_some() {
+        config.services.database = services.database.clone();
+    }
+    if let Some(traefik) = services.traefik {
+        config.services.traefik = traefik;
+    }
+    if let Some(dashboard) = services.dashboard {
+        config.services.dashboard = dashboard;
+    }
+
+    if ip_adress.is_some() {
+        config.ip_adress = ip_adress.clone();
+    }
+    if project_folder_path.is_some() {
+        config.project_folder_path = project_folder_path.clone();
+    }
+    if project_entry_file_path.is_some() {
+        config.project_entry_file_path = project_entry_file_path.clone();
+    }
+    if ssl_certificate_path_key.is_some() {
+        config.ssl_certificate_path_key = ssl_certificate_path_key.clone();
+    }
+    if ssl_certificate_path_crt.is_some() {
+        config.ssl_certificate_path_crt = ssl_certificate_path_crt.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, recording_config};
+
+    const CLUSTER_FILE: &str = "version: 1\nip_adress: 10.0.0.1\nservices:\n  server: nginx\n  traefik: true\n";
+
+    // Configuration obtenue après `start <args>` sur un fichier de définition.
+    fn start_config(cluster_file: &str, args: &[&str]) -> ClusterConfig {
+        let cli = Cli::try_parse_from(["cluster_noodle", "start"].iter().chain(args)).unwrap();
+        let Some(Commands::Start {
+            services,
+            ip_adress,
+            project_folder_path,
+            project_entry_file_path,
+            ssl_certificate_path_key,
+            ssl_certificate_path_crt,
+            ..
+        }) = &cli.command
+        else {
+            panic!("start was not parsed");
+        };
+
+        let mut config = recording_config(cluster_file, &RecordingRunner::new());
+        apply_cli_overrides(
+            &mut config,
+            services,
+            ip_adress,
+            project_folder_path,
+            project_entry_file_path,
+            ssl_certificate_path_key,
+            ssl_certificate_path_crt,
+        );
+        config
+    }
+
+    #[test]
+    fn cli_options_override_the_cluster_file() {
+        let config = start_config(
+            CLUSTER_FILE,
+            &["--server", "apache", "--database", "postgresql", "--traefik=false", "-i", "10.0.0.9"],
+        );
+        assert_eq!(config.services.server, Some(ServerType::Apache));
+        assert_eq!(config.services.database, Some(DatabaseType::PostgreSQL));
+        assert!(!config.services.traefik);
+        assert_eq!(config.ip_adress.as_deref(), Some("10.0.0.9"));
+
+        let config = start_config("version: 1\n", &["--traefik", "--dashboard"]);
+        assert!(config.services.traefik);
+        assert!(config.services.dashboard);
+    }
+
+    #[test]
+    fn cluster_file_is_kept_without_cli_options() {
+        let config = start_config(CLUSTER_FILE, &[]);
+        assert_eq!(config.services.server, Some(ServerType::Nginx));
+        assert_eq!(config.services.database, None);
+        assert!(config.services.traefik);
+        assert!(!config.services.dashboard);
+        assert_eq!(config.ip_adress.as_deref(), Some("10.0.0.1"));
+    }
+}
@@ -4,12 +4,14 @@ use crate::services::apache::apache::ApacheConfig;
 use crate::services::nginx::nginx::NginxConfig;
 use crate::utils::envParsing::EnvConfig;
 use crate::utils::envVariables::EnvVariables;
+use crate::utils::runner::redact_secrets;
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::path::Path;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Service {
@@ -19,14 +21,95 @@ pub enum Service {
     Dashboard
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ServerType {
     Nginx,
     Apache,
     NodeJS,
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+impl ServerType {
+    // Nom du service dans le fichier docker-compose généré.
+    pub fn service_name(&self) -> &'static str {
+        match self {
+            ServerType::Nginx => "nginx",
+            ServerType::Apache => "apache",
+            ServerType::NodeJS => "node",
+        }
+    }
+
+    pub fn base_image(&self) -> &'static str {
+        match self {
+            ServerType::Nginx => "trafex/php-nginx:3.9.0",
+            ServerType::Apache => "webdevops/php-apache:8.4",
+            ServerType::NodeJS => "node:22",
+        }
+    }
+
+    // Fichier de configuration du serveur, intégré à l'image du projet.
+    pub fn config_path(&self) -> Option<String> {
+        match self {
+            ServerType::Nginx => Some(NginxConfig::get_config_path()),
+            ServerType::Apache => Some(ApacheConfig::get_vhost_config_path()),
+            ServerType::NodeJS => None,
+        }
+    }
+
+    // Emplacement du fichier de configuration dans le conteneur, monté ou copié dans l'image.
+    pub fn config_target(&self) -> Option<&'static str> {
+        match self {
+            ServerType::Nginx => Some("/etc/nginx/conf.d/server.conf"),
+            ServerType::Apache => Some("/opt/docker/etc/httpd/vhost.conf"),
+            ServerType::NodeJS => None,
+        }
+    }
+
+    // Dockerfile de l'image du projet, construite avec le dossier du projet comme contexte.
+    // Le fichier de configuration du serveur est fourni par le contexte nommé `config`.
+    pub fn project_dockerfile(&self) -> String {
+        let config_file = self
+            .config_path()
+            .and_then(|path| Some(Path::new(&path).file_name()?.to_string_lossy().to_string()))
+            .unwrap_or_default();
+
+        match self {
+            ServerType::Nginx => format!(
+                "FROM {}\nCOPY --from=config {} {}\nCOPY --chown=nobody . /var/www/html\n",
+                self.base_image(),
+                config_file,
+                self.config_target().unwrap_or_default()
+            ),
+            ServerType::Apache => format!(
+                "FROM {}\nCOPY --from=config {} {}\nCOPY --chown=application:application . /app\n",
+                self.base_image(),
+                config_file,
+                self.config_target().unwrap_or_default()
+            ),
+            ServerType::NodeJS => format!(
+                "FROM {}\nWORKDIR /app\nCOPY package*.json ./\nRUN npm install\nCOPY . .\nCMD [\"npm\", \"start\"]\n",
+                self.base_image()
+            ),
+        }
+    }
+}
+
+// Registre d'images déployé dans la stack. Son port est publié sur toutes les nodes par le
+// routing mesh, chaque démon docker le joint donc en local, sans TLS.
+pub const REGISTRY_ADDRESS: &str = "127.0.0.1:5000";
+
+// Image construite à partir du dossier du projet, hébergée par le registre du cluster.
+pub fn project_image(config: &ClusterConfig) -> Option<String> {
+    let server = config.services.server.as_ref()?;
+    config.project_folder_path.as_ref()?;
+    if config.sync_project {
+        return None;
+    }
+    Some(format!("{}/project-{}:latest", REGISTRY_ADDRESS, server.service_name()))
+}
+
+#[derive(Clone, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DatabaseType {
     #[value(name = "mysql")]
     MySQL,
@@ -36,10 +119,27 @@ pub enum DatabaseType {
     MongoDB,
 }
 
+impl DatabaseType {
+    // Volume nommé contenant les données de la base dans le fichier docker-compose généré.
+    pub fn data_volume(&self) -> &'static str {
+        match self {
+            DatabaseType::MySQL => "mysql_data",
+            DatabaseType::PostgreSQL => "postgres_data",
+            DatabaseType::MongoDB => "mongo_data",
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Services {
+    #[serde(default)]
     pub server: Option<ServerType>,
+    #[serde(default)]
     pub database: Option<DatabaseType>,
+    #[serde(default)]
     pub traefik: bool,
+    #[serde(default)]
     pub dashboard: bool,
 }
 
@@ -50,7 +150,7 @@ struct DockerComposeService {
     command: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     ports: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "is_none_or_empty")]
     volumes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     environment: Option<HashMap<String, String>>,
@@ -59,7 +159,23 @@ struct DockerComposeService {
     #[serde(skip_serializing_if = "Option::is_none")]
     depends_on: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    networks: Option<Vec<String>>
+    networks: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deploy: Option<DockerComposeDeploy>,
+}
+
+fn is_none_or_empty(values: &Option<Vec<String>>) -> bool {
+    values.as_ref().is_none_or(Vec::is_empty)
+}
+
+#[derive(Serialize, Deserialize)]
+struct DockerComposeDeploy {
+    placement: DockerComposePlacement,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DockerComposePlacement {
+    constraints: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -88,6 +204,9 @@ impl<'a> DockerComposeBuilder<'a> {
         if self.cluster_config.services.dashboard {
             self.add_dashboard_service();
         }
+        if project_image(self.cluster_config).is_some() {
+            self.add_registry_service()?;
+        }
 
         // Convertir le tout en yaml
         let yaml = serde_yaml::to_string(&self.compose)?;
@@ -115,6 +234,7 @@ impl<'a> DockerComposeBuilder<'a> {
             environment: None,
             depends_on: None,
             networks: None,
+            deploy: None,
         };
 
         self.compose
@@ -122,33 +242,75 @@ impl<'a> DockerComposeBuilder<'a> {
             .insert("traefik".to_string(), traefik_service);
     }
     
+    // Registre hébergeant l'image du projet, sur le manager où elle est construite et où ses
+    // données restent : replanifié sur un autre manager, il perdrait le volume et l'image.
+    fn add_registry_service(&mut self) -> Result<(), String> {
+        let hostname = self.cluster_config.local_hostname()?;
+        let registry_service = DockerComposeService {
+            image: "registry:2".to_string(),
+            command: None,
+            ports: Some(vec!["5000:5000".to_string()]),
+            volumes: Some(vec!["registry_data:/var/lib/registry".to_string()]),
+            environment: None,
+            labels: None,
+            depends_on: None,
+            networks: None,
+            deploy: Some(DockerComposeDeploy {
+                placement: DockerComposePlacement {
+                    constraints: vec![registry_constraint(&hostname)],
+                },
+            }),
+        };
+
+        self.cluster_config
+            .docker_images
+            .push(String::from("registry:2"));
+
+        self.compose
+            .services
+            .insert("registry".to_string(), registry_service);
+
+        self.compose
+            .volumes
+            .get_or_insert_with(HashMap::new)
+            .insert("registry_data".to_string(), Value::Mapping(serde_yaml::Mapping::new()));
+        Ok(())
+    }
+
     fn add_server_service(&mut self) {
         let server_type = self.cluster_config.services.server.clone();
+        // Le code et la configuration du serveur sont intégrés à l'image du projet, les
+        // tâches peuvent alors tourner sur n'importe quelle node.
+        let project_image = project_image(self.cluster_config);
         match server_type {
             Some(ServerType::Nginx) => {
                 // Volume qui concerne la config du serveur Nginx.
                 let conf_volume = format!(
-                    "{}:/etc/nginx/conf.d/server.conf:ro",
-                    NginxConfig::get_config_path()
+                    "{}:{}:ro",
+                    NginxConfig::get_config_path(),
+                    ServerType::Nginx.config_target().unwrap_or_default()
                 );
 
                 let mut nginx_service = DockerComposeService {
-                    image: "trafex/php-nginx:3.9.0".to_string(),
+                    image: project_image.clone().unwrap_or_else(|| ServerType::Nginx.base_image().to_string()),
                     labels: None,
                     command: None,
                     ports: Some(vec![format!("80:8080")]),
-                    volumes: Some(vec![conf_volume]),
+                    volumes: Some(if project_image.is_some() { vec![] } else { vec![conf_volume] }),
                     environment: None,
                     depends_on: None,
                     networks: None,
+                    deploy: None,
                 };
 
+                // L'image de base sert aussi à construire celle du projet sur le manager.
                 self.cluster_config
                     .docker_images
-                    .push(String::from("trafex/php-nginx:3.9.0"));
+                    .push(String::from(ServerType::Nginx.base_image()));
 
                 if let Some(ref mut volumes) = nginx_service.volumes
                     && self.cluster_config.project_folder_path.is_some()
+                    && project_image.is_none()
                 {
                     let absolute_path =
                         fs::canonicalize(self.cluster_config.project_folder_path.clone().unwrap())
@@ -204,27 +366,30 @@ impl<'a> DockerComposeBuilder<'a> {
             Some(ServerType::Apache) => {
                 // Volume qui concerne la config du serveur Apache.
                 let vhost_path_volume = format!(
-                    "{}:/opt/docker/etc/httpd/vhost.conf:ro",
-                    ApacheConfig::get_vhost_config_path()
+                    "{}:{}:ro",
+                    ApacheConfig::get_vhost_config_path(),
+                    ServerType::Apache.config_target().unwrap_or_default()
                 );
 
                 let mut apache_service = DockerComposeService {
-                    image: "webdevops/php-apache:8.4".to_string(),
+                    image: project_image.clone().unwrap_or_else(|| ServerType::Apache.base_image().to_string()),
                     labels: None,
                     command: None,
                     ports: Some(vec![format!("8080:80")]),
-                    volumes: Some(vec![vhost_path_volume]),
+                    volumes: Some(if project_image.is_some() { vec![] } else { vec![vhost_path_volume] }),
                     environment: None,
                     depends_on: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config
                     .docker_images
-                    .push(String::from("webdevops/php-apache:8.4"));
+                    .push(String::from(ServerType::Apache.base_image()));
 
                 if let Some(ref mut volumes) = apache_service.volumes
                     && self.cluster_config.project_folder_path.is_some()
+                    && project_image.is_none()
                 {
                     // Volume qui concerne le code à exécuter dans le serveur Apache.
                     let app_path =
@@ -286,19 +451,24 @@ impl<'a> DockerComposeBuilder<'a> {
 
             Some(ServerType::NodeJS) => {
                 let mut node_service = DockerComposeService {
-                    image: "node:22".to_string(),
+                    image: project_image.clone().unwrap_or_else(|| ServerType::NodeJS.base_image().to_string()),
                     labels: None,
-                    command: Some(vec!["bash -c 'npm install && npm start'".to_string()]),
+                    // L'image du projet installe les dépendances à sa construction.
+                    command: match project_image {
+                        Some(_) => None,
+                        None => Some(vec!["bash -c 'npm install && npm start'".to_string()]),
+                    },
                     ports: Some(vec!["3000:3000".to_string()]),
                     volumes: None,
                     environment: None,
                     depends_on: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config
                     .docker_images
-                    .push(String::from("node:22"));
+                    .push(String::from(ServerType::NodeJS.base_image()));
 
                 if self.cluster_config.services.traefik {
                     node_service.labels = Some(vec![
@@ -347,7 +517,8 @@ impl<'a> DockerComposeBuilder<'a> {
                     command: None,
                     labels: None,
                     depends_on: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config
@@ -373,7 +544,8 @@ impl<'a> DockerComposeBuilder<'a> {
                     command: None,
                     ports: None,
                     volumes: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config
@@ -417,7 +589,8 @@ impl<'a> DockerComposeBuilder<'a> {
                     command: None,
                     labels: None,
                     depends_on: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config
@@ -450,7 +623,8 @@ impl<'a> DockerComposeBuilder<'a> {
                     command: None,
                     labels: None,
                     depends_on: None,
-                    networks: None
+                    networks: None,
+                    deploy: None,
                 };
 
                 self.cluster_config.docker_images.push("mongo:7".to_owned());
@@ -582,7 +756,8 @@ impl<'a> DockerComposeBuilder<'a> {
             volumes: None,
             environment: Some(dashboard_frontend_env),
             labels: None,
-            networks: Some(vec![network_frontend_name.to_string()])
+            networks: Some(vec![network_frontend_name.to_string()]),
+            deploy: None,
         };
 
         self.cluster_config.docker_images.push(docker_backend_image_name.clone());
@@ -597,7 +772,8 @@ impl<'a> DockerComposeBuilder<'a> {
             volumes: None,
             environment: Some(dashboard_backend_env),
             labels: None,
-            networks: Some(vec![network_backend_name.to_string()])
+            networks: Some(vec![network_backend_name.to_string()]),
+            deploy: None,
         };
 
         self.cluster_config.docker_images.push(docker_agent_image_name.clone());
@@ -609,7 +785,8 @@ impl<'a> DockerComposeBuilder<'a> {
             volumes: Some(vec![format!("/var/run/docker.sock:/var/run/docker.sock")]),
             environment: None,
             labels: None,
-            networks: Some(vec![network_backend_name.to_string()])
+            networks: Some(vec![network_backend_name.to_string()]),
+            deploy: None,
         };
 
         self.compose.services.insert(dashboard_frontend_service_name.to_string(), dashboard_frontend_service);
@@ -618,7 +795,8 @@ impl<'a> DockerComposeBuilder<'a> {
     }
 }
 
-pub fn generate_docker_file(config: &mut ClusterConfig) -> io::Result<()> {
+// Contenu du fichier docker-compose correspondant aux services sélectionnés.
+pub fn render_docker_file(config: &mut ClusterConfig) -> Result<String, String> {
     // On met à jour le fichier de config en fonction des services sélectionnées
     let mut docker_compose_builder = DockerComposeBuilder {
         cluster_config: config,
@@ -630,24 +808,37 @@ pub fn generate_docker_file(config: &mut ClusterConfig) -> io::Result<()> {
             networks: Some(HashMap::new())
         },
     };
-    let docker_file_content = docker_compose_builder.generate_docker_compose();
-    match docker_file_content {
-        Ok(docker_file_content) => {
-            if let Err(_err) = create_docker_file(&docker_file_content) {
-                println!("Erreur lors de la génération du fichier docker file.");
-            }
-        }
-        Err(e) => println!(
-            "Erreur lors de la génération du fichier docker compose: {:?}",
-            e
-        ), // No need to return anything, just handle the error here.
+    docker_compose_builder
+        .generate_docker_compose()
+        .map_err(|e| format!("{:?}", e))
+}
+
+fn registry_constraint(hostname: &str) -> String {
+    format!("node.hostname == {}", hostname)
+}
+
+// Un bundle est rendu sur la machine qui le construit : le registre est replacé sur le
+// manager qui l'ouvre, celui qui construira et poussera l'image du projet.
+pub fn pin_registry(compose: &str, hostname: &str) -> Result<String, String> {
+    let mut compose: DockerCompose = serde_yaml::from_str(compose)
+        .map_err(|e| format!("Invalid docker-compose file : {}", e))?;
+    if let Some(registry) = compose.services.get_mut("registry") {
+        registry.deploy = Some(DockerComposeDeploy {
+            placement: DockerComposePlacement {
+                constraints: vec![registry_constraint(hostname)],
+            },
+        });
     }
-    Ok(())
+    serde_yaml::to_string(&compose).map_err(|e| format!("{:?}", e))
 }
 
-pub fn create_docker_file(dockerfile_content: &str) -> io::Result<()> {
-    let env = EnvVariables {};
-    match fs::write(&env.get_docker_file_path(), dockerfile_content) {
+pub fn print_docker_file(docker_file_path: &str, dockerfile_content: &str) {
+    println!("[dry-run] {} :", docker_file_path);
+    println!("{}", redact_secrets(dockerfile_content));
+}
+
+pub fn create_docker_file(docker_file_path: &str, dockerfile_content: &str) -> io::Result<()> {
+    match fs::write(docker_file_path, dockerfile_content) {
         Ok(ok) => ok,
         Err(err) => {
             println!("Error while creating : docker-compose.file : {}", err);
@@ -656,3 +847,321 @@ pub fn create_docker_file(dockerfile_content: &str) -> io::Result<()> {
     }
     Ok(())
 }
+
+// Port publié par un service du fichier docker-compose.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct PublishedPort {
+    pub service: String,
+    pub port: u16,
+}
+
+// Lit les ports publiés d'un fichier docker-compose, qu'il ait été généré par ClusterNoodle
+// ou fourni par l'utilisateur (syntaxe courte "80:8080" ou longue avec `published`).
+pub fn read_published_ports(compose_file_path: &str) -> Result<Vec<PublishedPort>, String> {
+    let contents = fs::read_to_string(compose_file_path)
+        .map_err(|e| format!("Failed to read {} : {}", compose_file_path, e))?;
+    let compose: Value = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Invalid docker-compose file ({}) : {}", compose_file_path, e))?;
+    Ok(published_ports(&compose))
+}
+
+fn published_ports(compose: &Value) -> Vec<PublishedPort> {
+    let mut published_ports = vec![];
+    let Some(services) = compose.get("services").and_then(Value::as_mapping) else {
+        return published_ports;
+    };
+
+    for (name, service) in services {
+        let Some(ports) = service.get("ports").and_then(Value::as_sequence) else {
+            continue;
+        };
+
+        for port in ports {
+            let published = match port {
+                // [ip:]publié:cible[/protocole], seul le port cible est donné s'il n'est pas publié.
+                Value::String(port) => {
+                    let port = port.split('/').next().unwrap_or_default();
+                    let parts: Vec<&str> = port.split(':').collect();
+                    if parts.len() < 2 { None } else { parts[parts.len() - 2].parse().ok() }
+                }
+                Value::Mapping(_) => port.get("published").and_then(|published| match published {
+                    Value::Number(number) => number.as_u64().and_then(|number| u16::try_from(number).ok()),
+                    Value::String(number) => number.parse().ok(),
+                    _ => None,
+                }),
+                _ => None,
+            };
+
+            if let Some(published) = published {
+                published_ports.push(PublishedPort {
+                    service: name.as_str().unwrap_or_default().to_string(),
+                    port: published,
+                });
+            }
+        }
+    }
+
+    published_ports.sort_by(|a, b| a.service.cmp(&b.service).then(a.port.cmp(&b.port)));
+    published_ports
+}
+
+// Différences entre deux fichiers docker-compose, service par service.
+#[derive(Default)]
+pub struct ComposeDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ComposeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// Compare les services de deux fichiers docker-compose. Un fichier vide est considéré comme
+// ne contenant aucun service.
+pub fn diff_compose(old_contents: &str, new_contents: &str) -> Result<ComposeDiff, String> {
+    let old_services = compose_services(old_contents)?;
+    let new_services = compose_services(new_contents)?;
+    let mut diff = ComposeDiff::default();
+
+    for (name, new_service) in &new_services {
+        match old_services.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(old_service) if old_service != new_service => diff.changed.push(name.clone()),
+            Some(_) => (),
+        }
+    }
+    for name in old_services.keys() {
+        if !new_services.contains_key(name) {
+            diff.removed.push(name.clone());
+        }
+    }
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    Ok(diff)
+}
+
+fn compose_services(contents: &str) -> Result<HashMap<String, Value>, String> {
+    if contents.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let compose: Value =
+        serde_yaml::from_str(contents).map_err(|e| format!("Invalid docker-compose file : {}", e))?;
+    Ok(compose
+        .get("services")
+        .and_then(Value::as_mapping)
+        .map(|services| {
+            services
+                .iter()
+                .filter_map(|(name, service)| Some((name.as_str()?.to_string(), service.clone())))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+// Image d'un service du fichier docker-compose et ses contraintes de placement.
+pub struct ServiceImage {
+    pub service: String,
+    pub image: String,
+    pub constraints: Vec<String>,
+}
+
+pub fn read_service_images(compose_file_path: &str) -> Result<Vec<ServiceImage>, String> {
+    let contents = fs::read_to_string(compose_file_path)
+        .map_err(|e| format!("Failed to read {} : {}", compose_file_path, e))?;
+    let compose: Value = serde_yaml::from_str(&contents)
+        .map_err(|e| format!("Invalid docker-compose file ({}) : {}", compose_file_path, e))?;
+
+    let mut service_images = vec![];
+    let Some(services) = compose.get("services").and_then(Value::as_mapping) else {
+        return Ok(service_images);
+    };
+
+    for (name, service) in services {
+        let Some(image) = service.get("image").and_then(Value::as_str) else {
+            continue;
+        };
+        let constraints = service
+            .get("deploy")
+            .and_then(|deploy| deploy.get("placement"))
+            .and_then(|placement| placement.get("constraints"))
+            .and_then(Value::as_sequence)
+            .map(|constraints| {
+                constraints
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        service_images.push(ServiceImage {
+            service: name.as_str().unwrap_or_default().to_string(),
+            image: image.to_string(),
+            constraints,
+        });
+    }
+
+    service_images.sort_by(|a, b| a.service.cmp(&b.service));
+    Ok(service_images)
+}
+
+// Fichiers et dossiers de la machine montés dans les services (configuration du serveur,
+// certificats, code du projet), hors socket docker.
+pub fn read_bind_mounts(contents: &str) -> Result<Vec<String>, String> {
+    let mut bind_mounts = vec![];
+    for service in compose_services(contents)?.values() {
+        let Some(volumes) = service.get("volumes").and_then(Value::as_sequence) else {
+            continue;
+        };
+
+        for volume in volumes {
+            let source = match volume {
+                // source:cible[:mode], un volume nommé ne commence pas par /.
+                Value::String(volume) => volume.split(':').next(),
+                Value::Mapping(_) if volume.get("type").and_then(Value::as_str) == Some("bind") => {
+                    volume.get("source").and_then(Value::as_str)
+                }
+                _ => None,
+            };
+
+            if let Some(source) = source
+                && source.starts_with('/')
+                && source != "/var/run/docker.sock"
+            {
+                bind_mounts.push(source.to_string());
+            }
+        }
+    }
+
+    bind_mounts.sort();
+    bind_mounts.dedup();
+    Ok(bind_mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, recording_config};
+
+    #[test]
+    fn project_image_copies_the_config_where_it_was_mounted() {
+        for (server, server_name) in [(ServerType::Nginx, "nginx"), (ServerType::Apache, "apache")] {
+            let runner = RecordingRunner::new();
+            let mut config = recording_config(&format!("version: 1\nservices:\n  server: {}\n", server_name), &runner);
+            let compose = render_docker_file(&mut config).unwrap();
+
+            let config_path = server.config_path().unwrap();
+            let config_file = Path::new(&config_path).file_name().unwrap().to_string_lossy().to_string();
+            let target = server.config_target().unwrap();
+            assert!(compose.contains(&format!("{}:{}:ro", config_path, target)), "{}", compose);
+            assert!(
+                server.project_dockerfile().contains(&format!("COPY --from=config {} {}\n", config_file, target)),
+                "{}",
+                server.project_dockerfile()
+            );
+        }
+    }
+
+    #[test]
+    fn registry_is_pinned_to_the_manager_building_the_project_image() {
+        let yaml = "version: 1\nservices:\n  server: nginx\nproject_folder_path: /tmp\n";
+        let runner = RecordingRunner::new();
+        runner.respond("docker info --format {{.Name}}", 0, "manager-1\n", "");
+        let mut config = recording_config(yaml, &runner);
+        let compose = render_docker_file(&mut config).unwrap();
+        assert!(compose.contains("node.hostname == manager-1"), "{}", compose);
+        assert!(!compose.contains("node.role == manager"), "{}", compose);
+
+        // Un bundle ouvert sur un autre manager y replace le registre.
+        let pinned = pin_registry(&compose, "manager-2").unwrap();
+        assert!(pinned.contains("node.hostname == manager-2"), "{}", pinned);
+        assert!(!pinned.contains("manager-1"), "{}", pinned);
+        assert!(pinned.contains("registry_data:/var/lib/registry"), "{}", pinned);
+
+        let runner = RecordingRunner::new();
+        runner.respond("docker info --format {{.Name}}", 1, "", "Cannot connect to the Docker daemon");
+        let mut config = recording_config(yaml, &runner);
+        let error = render_docker_file(&mut config).err().unwrap();
+        assert!(error.contains("hostname of the manager"), "{}", error);
+    }
+
+    #[test]
+    fn diff_compose_lists_services_by_change() {
+        let base = "services:\n  web:\n    image: web:1\n  db:\n    image: postgres:16\n";
+        let cases = [
+            ("", base, vec!["db", "web"], vec![], vec![]),
+            (base, "", vec![], vec!["db", "web"], vec![]),
+            (base, base, vec![], vec![], vec![]),
+            (
+                base,
+                "services:\n  web:\n    image: web:2\n  db:\n    image: postgres:16\n",
+                vec![],
+                vec![],
+                vec!["web"],
+            ),
+            (
+                base,
+                "services:\n  web:\n    image: web:1\n    ports:\n      - \"80:8080\"\n  cache:\n    image: redis:7\n",
+                vec!["cache"],
+                vec!["db"],
+                vec!["web"],
+            ),
+            // L'ordre des clés ne compte pas.
+            (base, "services:\n  db:\n    image: postgres:16\n  web:\n    image: web:1\n", vec![], vec![], vec![]),
+            // Un fichier sans section services ne contient aucun service.
+            ("version: '3.8'\n", base, vec!["db", "web"], vec![], vec![]),
+        ];
+
+        for (old_contents, new_contents, added, removed, changed) in cases {
+            let diff = diff_compose(old_contents, new_contents).unwrap();
+            assert_eq!(diff.added, added, "{:?} -> {:?}", old_contents, new_contents);
+            assert_eq!(diff.removed, removed, "{:?} -> {:?}", old_contents, new_contents);
+            assert_eq!(diff.changed, changed, "{:?} -> {:?}", old_contents, new_contents);
+            assert_eq!(diff.is_empty(), added.is_empty() && removed.is_empty() && changed.is_empty());
+        }
+    }
+
+    #[test]
+    fn diff_compose_rejects_invalid_files() {
+        assert!(diff_compose("services: [", "").is_err());
+        assert!(diff_compose("", "services:\n  web: {\n").is_err());
+    }
+
+    fn published(service: &str, port: u16) -> PublishedPort {
+        PublishedPort { service: service.to_string(), port }
+    }
+
+    fn parse_published_ports(contents: &str) -> Vec<PublishedPort> {
+        published_ports(&serde_yaml::from_str(contents).unwrap())
+    }
+
+    #[test]
+    fn published_ports_short_syntax() {
+        let compose = "services:\n  web:\n    ports:\n      - \"80:8080\"\n      - \"127.0.0.1:443:8443/tcp\"\n      - \"9000\"\n      - \"53:53/udp\"\n";
+        assert_eq!(
+            parse_published_ports(compose),
+            vec![published("web", 53), published("web", 80), published("web", 443)]
+        );
+    }
+
+    #[test]
+    fn published_ports_long_syntax() {
+        let compose = "services:\n  proxy:\n    ports:\n      - target: 80\n        published: 8080\n        mode: host\n      - target: 443\n        published: \"8443\"\n        mode: ingress\n      - target: 9000\n        mode: ingress\n  api:\n    image: api\n  db:\n    ports:\n      - target: 5432\n        published: 5432\n        protocol: tcp\n";
+        assert_eq!(
+            parse_published_ports(compose),
+            vec![published("db", 5432), published("proxy", 8080), published("proxy", 8443)]
+        );
+    }
+
+    #[test]
+    fn published_ports_without_services() {
+        assert_eq!(parse_published_ports("version: '3.8'\n"), vec![]);
+        assert_eq!(parse_published_ports("services:\n  web:\n    ports: []\n"), vec![]);
+    }
+}
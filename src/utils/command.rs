@@ -1,20 +1,208 @@
-use std::io;
-use std::process::{Command, Output};
-use std::sync::mpsc;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub fn run_with_timeout(mut command: Command, timeout: Duration) -> io::Result<Option<Output>> {
-    let (sender, receiver) = mpsc::channel();
+// Taille maximale conservée pour chaque sortie, seule la fin de la sortie est gardée.
+const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Durée laissée pour lire la fin des sorties une fois la commande terminée. Un processus
+// lancé en arrière-plan par la commande peut garder les sorties ouvertes indéfiniment.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
 
-    thread::spawn(move || {
-        let result = command.output(); // This is blocking
-        let _ = sender.send(result);
-    });
+// Résultat d'une commande locale.
+pub struct CommandOutcome {
+    // None si le processus a été tué (timeout ou signal).
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+    pub timed_out: bool,
+    // Vrai si une des sorties a dépassé MAX_CAPTURED_OUTPUT et a été tronquée, ou n'a pas
+    // pu être lue jusqu'au bout.
+    pub truncated: bool,
+}
+
+impl CommandOutcome {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+// Lance la commande dans son propre groupe de processus en affichant sa sortie au fil de
+// l'eau. Si le timeout est dépassé, tout le groupe est tué pour ne laisser aucun processus
+// orphelin (par exemple une session ssh bloquée).
+pub fn run_with_timeout(command: Command, timeout: Duration) -> io::Result<CommandOutcome> {
+    execute(command, timeout, true)
+}
+
+// Même chose sans afficher la sortie, pour les commandes dont on exploite le résultat.
+pub fn capture_with_timeout(command: Command, timeout: Duration) -> io::Result<CommandOutcome> {
+    execute(command, timeout, false)
+}
+
+fn execute(mut command: Command, timeout: Duration, stream: bool) -> io::Result<CommandOutcome> {
+    let started_at = Instant::now();
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let captured_stdout = Arc::new(Mutex::new(CapturedOutput::default()));
+    let captured_stderr = Arc::new(Mutex::new(CapturedOutput::default()));
+    let (done_sender, done) = mpsc::channel();
+    for (output, captured, echo) in [
+        (Box::new(stdout) as Box<dyn Read + Send>, captured_stdout.clone(), stream.then_some(false)),
+        (Box::new(stderr), captured_stderr.clone(), stream.then_some(true)),
+    ] {
+        let done_sender = done_sender.clone();
+        thread::spawn(move || {
+            read_output(output, echo, &captured);
+            let _ = done_sender.send(());
+        });
+    }
+    drop(done_sender);
+
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if started_at.elapsed() >= timeout {
+            timed_out = true;
+            kill_process_group(child.id());
+            break child.wait()?;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    // Les lecteurs encore bloqués sont abandonnés avec ce qu'ils ont lu.
+    let drain_deadline = Instant::now() + OUTPUT_DRAIN_TIMEOUT;
+    let mut drained = 0;
+    while drained < 2 {
+        match done.recv_timeout(drain_deadline.saturating_duration_since(Instant::now())) {
+            Ok(()) => drained += 1,
+            Err(_) => break,
+        }
+    }
+
+    let stdout = take_output(&captured_stdout);
+    let stderr = take_output(&captured_stderr);
+    Ok(CommandOutcome {
+        exit_code: if timed_out { None } else { status.code() },
+        duration: started_at.elapsed(),
+        stdout: stdout.text,
+        stderr: stderr.text,
+        timed_out,
+        truncated: stdout.truncated || stderr.truncated || drained < 2,
+    })
+}
+
+#[derive(Default)]
+struct CapturedOutput {
+    text: String,
+    truncated: bool,
+}
+
+fn take_output(captured: &Mutex<CapturedOutput>) -> CapturedOutput {
+    std::mem::take(&mut *captured.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+// `echo` vaut None si la sortie ne doit pas être affichée, Some(true) pour stderr. La sortie
+// est lue jusqu'au bout même si elle n'est pas en UTF-8, sans quoi la commande pourrait être
+// tuée par SIGPIPE.
+fn read_output<R: Read>(output: R, echo: Option<bool>, captured: &Mutex<CapturedOutput>) {
+    let mut reader = BufReader::new(output);
+    let mut buffer = Vec::new();
+
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        match echo {
+            Some(true) => eprintln!("{}", line),
+            Some(false) => println!("{}", line),
+            None => (),
+        }
+
+        let mut captured = captured.lock().unwrap_or_else(|e| e.into_inner());
+        captured.text.push_str(line);
+        captured.text.push('\n');
+        if captured.text.len() > MAX_CAPTURED_OUTPUT {
+            let mut cut = captured.text.len() - MAX_CAPTURED_OUTPUT;
+            while !captured.text.is_char_boundary(cut) {
+                cut += 1;
+            }
+            captured.text.drain(..cut);
+            captured.truncated = true;
+        }
+    }
+}
+
+fn kill_process_group(pid: u32) {
+    // Le groupe de processus a le même identifiant que le processus lancé (process_group(0)).
+    // SAFETY: kill ne fait qu'envoyer un signal, il n'accède à aucune mémoire du programme.
+    // Le pid est celui d'un enfant qui n'a pas encore été attendu (wait), il ne peut donc
+    // pas avoir été réattribué à un autre groupe de processus.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn output_after_invalid_utf8_is_kept() {
+        let outcome = capture_with_timeout(shell("printf 'a\\n\\377\\nb\\r\\n' && printf 'c\\n' >&2"), Duration::from_secs(10)).unwrap();
+
+        assert!(outcome.success());
+        assert_eq!(outcome.stdout, "a\n\u{FFFD}\nb\n");
+        assert_eq!(outcome.stderr, "c\n");
+        assert!(!outcome.truncated);
+    }
+
+    #[test]
+    fn background_process_holding_the_output_does_not_block() {
+        let started_at = Instant::now();
+        let outcome = capture_with_timeout(shell("sleep 5 & echo started"), Duration::from_secs(10)).unwrap();
+
+        assert!(outcome.success());
+        assert_eq!(outcome.stdout, "started\n");
+        assert!(outcome.truncated);
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn timeout_kills_the_process_group() {
+        let outcome = capture_with_timeout(shell("echo started; sleep 30"), Duration::from_millis(300)).unwrap();
 
-    match receiver.recv_timeout(timeout) {
-        Ok(result) => result.map(Some),                   // Command finished
-        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None), // Timeout occurred
-        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)), // Other errors
+        assert!(outcome.timed_out);
+        assert_eq!(outcome.exit_code, None);
+        assert_eq!(outcome.stdout, "started\n");
     }
 }
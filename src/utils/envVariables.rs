@@ -13,7 +13,27 @@ impl EnvVariables {
     }
 
     pub fn get_conf_file_path(&self) -> String {
-        return format!("{}/conf.cluster_noodle", self.get_conf_path());
+        return format!("{}/cluster.yml", self.get_conf_path());
+    }
+
+    pub fn get_state_file_path(&self) -> String {
+        return format!("{}/state.json", self.get_conf_path());
+    }
+
+    pub fn get_known_hosts_file_path(&self) -> String {
+        return format!("{}/known_hosts", self.get_conf_path());
+    }
+
+    pub fn get_images_folder_path(&self) -> String {
+        return format!("{}/images", self.get_conf_path());
+    }
+
+    pub fn get_bundle_folder_path(&self) -> String {
+        return format!("{}/bundle", self.get_conf_path());
+    }
+
+    pub fn get_project_dockerfile_path(&self) -> String {
+        return format!("{}/Dockerfile.project", self.get_conf_path());
     }
 
     pub fn get_env_file_path(&self) -> String {
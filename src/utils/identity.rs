@@ -0,0 +1,99 @@
+#![allow(dead_code)]
+use crate::config::config::NodeConfig;
+use crate::utils::runner::CommandRunner;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+// Identité SSH utilisée pour se connecter à une node.
+pub enum SshIdentity {
+    KeyFile(PathBuf),
+    Agent,
+}
+
+impl SshIdentity {
+    pub fn describe(&self) -> String {
+        match self {
+            SshIdentity::KeyFile(path) => path.display().to_string(),
+            SshIdentity::Agent => String::from("ssh-agent"),
+        }
+    }
+}
+
+pub fn get_default_identity_path() -> PathBuf {
+    let mut key_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
+    key_path.push(".ssh/cluster_noodle");
+    key_path
+}
+
+pub fn get_public_key_path(private_key_path: &Path) -> PathBuf {
+    let mut public_key_path = private_key_path.as_os_str().to_owned();
+    public_key_path.push(".pub");
+    PathBuf::from(public_key_path)
+}
+
+pub fn check_existing_identity() -> bool {
+    let private_key = get_default_identity_path();
+    let public_key = get_public_key_path(&private_key);
+
+    private_key.exists() && public_key.exists()
+}
+
+pub fn generate_identity(runner: &dyn CommandRunner) {
+    let key_path = get_default_identity_path();
+    if let Some(ssh_dir) = key_path.parent() {
+        let _ = fs::create_dir_all(ssh_dir);
+    }
+    let key_path_str = key_path.to_str().unwrap();
+
+    let mut cmd = Command::new("ssh-keygen");
+    cmd.args(["-t", "ed25519", "-C", "ClusterNoodle", "-f", key_path_str, "-N", ""]);
+    let output = runner
+        .output(cmd, Duration::from_secs(60))
+        .unwrap_or_else(|_| {
+            panic!(
+                "Failed to execute ssh-keygen for in the following path : {}",
+                key_path_str
+            )
+        });
+
+    if !output.success() {
+        println!("Erreur de génération de clé : {}", output.stderr);
+    }
+}
+
+// Une node peut utiliser sa propre clé ou l'agent SSH, sinon on utilise la clé
+// générée par ClusterNoodle.
+pub fn resolve_identity(node_config: &NodeConfig) -> SshIdentity {
+    if node_config.ssh_agent {
+        return SshIdentity::Agent;
+    }
+
+    match &node_config.identity_file {
+        Some(path) => SshIdentity::KeyFile(expand_home(path)),
+        None => SshIdentity::KeyFile(get_default_identity_path()),
+    }
+}
+
+// Clé publique à installer sur la node, aucune pour l'agent SSH qui gère ses propres clés.
+pub fn read_public_key(identity: &SshIdentity) -> Result<Option<String>, String> {
+    match identity {
+        SshIdentity::Agent => Ok(None),
+        SshIdentity::KeyFile(private_key_path) => {
+            let public_key_path = get_public_key_path(private_key_path);
+            fs::read_to_string(&public_key_path)
+                .map(|key| Some(key.trim().to_string()))
+                .map_err(|e| format!("Failed to read {} : {}", public_key_path.display(), e))
+        }
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(relative_path) => dirs::home_dir()
+            .unwrap_or_else(|| PathBuf::from("/"))
+            .join(relative_path),
+        None => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,78 @@
+use crate::config::config::NodeConfig;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// Résultat d'une étape exécutée sur une node.
+pub struct NodeResult {
+    pub ip: String,
+    pub result: Result<String, String>,
+}
+
+// Affiche chaque ligne préfixée par la node concernée pour garder une sortie lisible
+// lorsque plusieurs nodes travaillent en même temps.
+pub fn node_log(ip: &str, message: &str) {
+    for line in message.lines().filter(|line| !line.trim().is_empty()) {
+        println!("[{}] {}", ip, line);
+    }
+}
+
+// Exécute `task` sur chaque node, au plus `parallelism` nodes à la fois.
+// Les résultats sont renvoyés dans l'ordre des nodes.
+pub fn run_on_nodes<F>(nodes: &[&NodeConfig], parallelism: usize, task: F) -> Vec<NodeResult>
+where
+    F: Fn(&NodeConfig) -> Result<String, String> + Sync,
+{
+    let next_node = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<NodeResult>>> =
+        Mutex::new((0..nodes.len()).map(|_| None).collect());
+    let workers = parallelism.max(1).min(nodes.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next_node.fetch_add(1, Ordering::SeqCst);
+                    let Some(node_config) = nodes.get(index) else {
+                        break;
+                    };
+
+                    let result = task(node_config);
+                    match &result {
+                        Ok(message) => node_log(&node_config.ip, message),
+                        Err(error) => node_log(&node_config.ip, &format!("Error: {}", error)),
+                    }
+
+                    results.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(NodeResult {
+                        ip: node_config.ip.clone(),
+                        result,
+                    });
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+// Résumé d'une étape : nombre de nodes en succès et détail des échecs.
+pub fn print_report(stage: &str, results: &[NodeResult]) {
+    let failures: Vec<&NodeResult> = results.iter().filter(|node| node.result.is_err()).collect();
+
+    println!(
+        "{} : {}/{} node(s) succeeded",
+        stage,
+        results.len() - failures.len(),
+        results.len()
+    );
+    for failure in failures {
+        if let Err(error) = &failure.result {
+            println!("  - {} : {}", failure.ip, error);
+        }
+    }
+}
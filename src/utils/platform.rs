@@ -0,0 +1,530 @@
+use crate::utils::runner::RemoteExecutor;
+
+// Informations sur le système d'une node, tirées de /etc/os-release.
+// Distributions compatibles RHEL, dont les paquets valent pour toute une version majeure.
+const RHEL_IDS: [&str; 5] = ["rhel", "centos", "rocky", "almalinux", "ol"];
+
+pub struct OsInfo {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub version_id: String,
+    pub pretty_name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Yum,
+    Apk,
+    Zypper,
+}
+
+impl OsInfo {
+    pub fn parse(os_release: &str) -> OsInfo {
+        let mut os_info = OsInfo {
+            id: String::new(),
+            id_like: vec![],
+            version_id: String::new(),
+            pretty_name: String::new(),
+        };
+
+        for line in os_release.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').trim_matches('\'');
+            match key.trim() {
+                "ID" => os_info.id = value.to_lowercase(),
+                "ID_LIKE" => os_info.id_like = value.split_whitespace().map(|id| id.to_lowercase()).collect(),
+                "VERSION_ID" => os_info.version_id = value.to_string(),
+                "PRETTY_NAME" => os_info.pretty_name = value.to_string(),
+                _ => (),
+            }
+        }
+
+        os_info
+    }
+
+    pub fn describe(&self) -> String {
+        if self.pretty_name.is_empty() {
+            format!("{} {}", self.id, self.version_id)
+        } else {
+            self.pretty_name.clone()
+        }
+    }
+
+    // Identifiant de la distribution suivi de celles dont elle dérive.
+    fn ids(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.id.as_str()).chain(self.id_like.iter().map(String::as_str))
+    }
+
+    fn major_version(&self) -> u32 {
+        self.version_id
+            .split('.')
+            .next()
+            .and_then(|major| major.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn is_fedora(&self) -> bool {
+        self.id == "fedora"
+    }
+
+    fn is_rhel(&self) -> bool {
+        self.ids().any(|id| RHEL_IDS.contains(&id))
+    }
+
+    pub fn package_manager(&self) -> Result<PackageManager, String> {
+        for id in self.ids() {
+            match id {
+                "debian" | "ubuntu" => return Ok(PackageManager::Apt),
+                "fedora" => return Ok(PackageManager::Dnf),
+                id if RHEL_IDS.contains(&id) => {
+                    // dnf remplace yum à partir de RHEL 8.
+                    return Ok(if self.major_version() >= 8 || self.major_version() == 0 {
+                        PackageManager::Dnf
+                    } else {
+                        PackageManager::Yum
+                    });
+                }
+                "alpine" => return Ok(PackageManager::Apk),
+                "suse" | "opensuse" | "sles" => return Ok(PackageManager::Zypper),
+                id if id.starts_with("opensuse") => return Ok(PackageManager::Zypper),
+                _ => (),
+            }
+        }
+
+        Err(format!("Unsupported platform : {}", self.describe()))
+    }
+
+    // Commande installant docker et démarrant le service au boot. Une version précise n'est
+    // disponible que dans les dépôts de Docker (docker-ce), ceux des distributions ne
+    // fournissent que leur propre version.
+    pub fn docker_install_command(&self, version: Option<&str>) -> Result<String, String> {
+        let package_manager = self.package_manager()?;
+        if let Some(version) = version {
+            return self.pinned_docker_install_command(package_manager, version);
+        }
+
+        let command = match package_manager {
+            PackageManager::Apt => "apt-get update -y && apt-get install -y docker.io && systemctl enable --now docker",
+            // Fedora fournit docker dans ses dépôts (moby-engine), pas les distributions RHEL.
+            PackageManager::Dnf if self.is_fedora() => "dnf install -y moby-engine && systemctl enable --now docker",
+            PackageManager::Dnf => {
+                "dnf install -y dnf-plugins-core && dnf config-manager --add-repo https://download.docker.com/linux/centos/docker-ce.repo && dnf install -y docker-ce docker-ce-cli containerd.io && systemctl enable --now docker"
+            }
+            PackageManager::Yum => {
+                "yum install -y yum-utils && yum-config-manager --add-repo https://download.docker.com/linux/centos/docker-ce.repo && yum install -y docker-ce docker-ce-cli containerd.io && systemctl enable --now docker"
+            }
+            PackageManager::Apk => "apk add --no-cache docker && rc-update add docker default && service docker start",
+            PackageManager::Zypper => "zypper --non-interactive install docker && systemctl enable --now docker",
+        };
+        Ok(command.to_string())
+    }
+
+    fn pinned_docker_install_command(&self, package_manager: PackageManager, version: &str) -> Result<String, String> {
+        let command = match package_manager {
+            PackageManager::Apt => format!(
+                "{} && {} && apt-get install -y --allow-downgrades docker-ce=$PACKAGE_VERSION docker-ce-cli=$PACKAGE_VERSION containerd.io \
+                 && systemctl enable --now docker",
+                self.docker_ce_repository_command(package_manager)?,
+                package_version(package_manager, version)
+            ),
+            // yum (CentOS 7) ne connaît pas --allowerasing.
+            PackageManager::Dnf | PackageManager::Yum => format!(
+                "{} && {} && {} install -y{} docker-ce-$PACKAGE_VERSION docker-ce-cli-$PACKAGE_VERSION containerd.io \
+                 && systemctl enable --now docker",
+                self.docker_ce_repository_command(package_manager)?,
+                package_version(package_manager, version),
+                package_manager_tool(package_manager),
+                if package_manager == PackageManager::Dnf { " --allowerasing" } else { "" }
+            ),
+            // `~` sélectionne la version la plus récente commençant par `version`.
+            PackageManager::Apk => format!(
+                "apk add --no-cache 'docker~{version}' && rc-update add docker default && service docker start"
+            ),
+            PackageManager::Zypper => format!(
+                "{} && zypper --non-interactive install --oldpackage docker=$PACKAGE_VERSION && systemctl enable --now docker",
+                package_version(package_manager, version)
+            ),
+        };
+        Ok(command)
+    }
+
+    // Ajoute le dépôt docker-ce de la distribution.
+    fn docker_ce_repository_command(&self, package_manager: PackageManager) -> Result<String, String> {
+        match package_manager {
+            PackageManager::Apt => {
+                let Some(repository) = self.ids().find(|id| *id == "debian" || *id == "ubuntu") else {
+                    return Err(format!("No docker-ce repository for {}", self.describe()));
+                };
+                Ok(format!(
+                    "apt-get update -y && apt-get install -y ca-certificates curl && install -m 0755 -d /etc/apt/keyrings \
+                     && curl -fsSL https://download.docker.com/linux/{repository}/gpg -o /etc/apt/keyrings/docker.asc \
+                     && echo \"deb [signed-by=/etc/apt/keyrings/docker.asc] https://download.docker.com/linux/{repository} $(. /etc/os-release && echo ${{UBUNTU_CODENAME:-$VERSION_CODENAME}}) stable\" > /etc/apt/sources.list.d/docker.list \
+                     && apt-get update -y"
+                ))
+            }
+            PackageManager::Dnf | PackageManager::Yum => {
+                let repository = if self.is_fedora() { "fedora" } else { "centos" };
+                Ok(format!(
+                    "curl -fsSL https://download.docker.com/linux/{repository}/docker-ce.repo -o /etc/yum.repos.d/docker-ce.repo"
+                ))
+            }
+            PackageManager::Apk | PackageManager::Zypper => {
+                Err(format!("No docker-ce repository for {}", self.describe()))
+            }
+        }
+    }
+
+    // Clé des paquets docker d'une distribution dans un bundle hors ligne (debian-12). Les
+    // versions mineures d'une distribution RHEL partagent leurs paquets (rocky-9 sert 9.4 et
+    // 9.5), les autres distributions ont un dépôt par version majeure.mineure (alpine-3.20).
+    pub fn bundle_key(&self) -> String {
+        let parts = if self.is_rhel() { 1 } else { 2 };
+        let version: Vec<&str> = self.version_id.split('.').take(parts).collect();
+        format!("{}-{}", self.id, version.join("."))
+    }
+
+    // Commande téléchargeant, sans les installer, les paquets docker et leurs dépendances
+    // dans `folder`. Elle est lancée dans un conteneur de la distribution visée.
+    pub fn docker_download_command(&self, version: Option<&str>, folder: &str) -> Result<String, String> {
+        let package_manager = self.package_manager()?;
+        let command = match (package_manager, version) {
+            (PackageManager::Apt, None) => format!(
+                "mkdir -p {folder}/partial && apt-get update -y \
+                 && apt-get install -y --download-only -o Dir::Cache::archives={folder} docker.io \
+                 && rm -rf {folder}/partial {folder}/lock"
+            ),
+            (PackageManager::Apt, Some(version)) => format!(
+                "mkdir -p {folder}/partial && {} && {} \
+                 && apt-get install -y --download-only -o Dir::Cache::archives={folder} docker-ce=$PACKAGE_VERSION docker-ce-cli=$PACKAGE_VERSION containerd.io \
+                 && rm -rf {folder}/partial {folder}/lock",
+                self.docker_ce_repository_command(package_manager)?,
+                package_version(package_manager, version)
+            ),
+            (PackageManager::Dnf, None) if self.is_fedora() => {
+                format!("dnf install -y --downloadonly --destdir {folder} moby-engine")
+            }
+            (PackageManager::Dnf | PackageManager::Yum, None) => format!(
+                "{} && {} install -y --downloadonly --downloaddir={folder} docker-ce docker-ce-cli containerd.io",
+                self.docker_ce_repository_command(package_manager)?,
+                package_manager_tool(package_manager)
+            ),
+            (PackageManager::Dnf | PackageManager::Yum, Some(version)) => format!(
+                "{} && {} && {} install -y --downloadonly --downloaddir={folder} docker-ce-$PACKAGE_VERSION docker-ce-cli-$PACKAGE_VERSION containerd.io",
+                self.docker_ce_repository_command(package_manager)?,
+                package_version(package_manager, version),
+                package_manager_tool(package_manager)
+            ),
+            (PackageManager::Apk, None) => format!("apk update && apk fetch --recursive --output {folder} docker"),
+            (PackageManager::Apk, Some(version)) => {
+                format!("apk update && apk fetch --recursive --output {folder} 'docker~{version}'")
+            }
+            (PackageManager::Zypper, None) => {
+                format!("zypper --non-interactive --pkg-cache-dir {folder} install --download-only docker")
+            }
+            (PackageManager::Zypper, Some(version)) => format!(
+                "{} && zypper --non-interactive --pkg-cache-dir {folder} install --download-only docker=$PACKAGE_VERSION",
+                package_version(package_manager, version)
+            ),
+        };
+        Ok(command)
+    }
+
+    // Installe docker à partir des paquets d'un bundle copiés dans `folder`, sans accès au
+    // réseau, puis démarre le service.
+    pub fn offline_docker_install_command(&self, folder: &str) -> Result<String, String> {
+        let command = match self.package_manager()? {
+            PackageManager::Apt => format!("apt-get install -y --allow-downgrades --no-download {folder}/*.deb"),
+            PackageManager::Dnf => {
+                format!("dnf install -y --disablerepo='*' --allowerasing $(find {folder} -name '*.rpm')")
+            }
+            PackageManager::Yum => format!("yum localinstall -y --disablerepo='*' $(find {folder} -name '*.rpm')"),
+            PackageManager::Apk => format!("apk add --no-network --allow-untrusted {folder}/*.apk"),
+            PackageManager::Zypper => format!(
+                "zypper --non-interactive --no-refresh install --allow-unsigned-rpm $(find {folder} -name '*.rpm')"
+            ),
+        };
+        Ok(format!("{} && {}", command, self.docker_start_command()?))
+    }
+
+    pub fn install_packages_command(&self, packages: &[&str]) -> Result<String, String> {
+        let packages = packages.join(" ");
+        let command = match self.package_manager()? {
+            PackageManager::Apt => format!("apt-get update -y && apt-get install -y {}", packages),
+            PackageManager::Dnf => format!("dnf install -y {}", packages),
+            PackageManager::Yum => format!("yum install -y {}", packages),
+            PackageManager::Apk => format!("apk add --no-cache {}", packages),
+            PackageManager::Zypper => format!("zypper --non-interactive install {}", packages),
+        };
+        Ok(command)
+    }
+
+    // Démarre le démon docker et l'active au boot.
+    pub fn docker_start_command(&self) -> Result<&'static str, String> {
+        Ok(match self.package_manager()? {
+            PackageManager::Apk => "rc-update add docker default && service docker start",
+            _ => "systemctl enable --now docker",
+        })
+    }
+
+    pub fn add_user_to_group_command(&self, username: &str, group: &str) -> Result<String, String> {
+        Ok(match self.package_manager()? {
+            PackageManager::Apk => format!("addgroup {} {}", username, group),
+            _ => format!("usermod -aG {} {}", group, username),
+        })
+    }
+
+    // Commande désinstallant docker, qu'il vienne des dépôts de la distribution ou de ceux de
+    // Docker. Ses données sont supprimées séparément.
+    pub fn docker_purge_command(&self) -> Result<String, String> {
+        let command = match self.package_manager()? {
+            PackageManager::Apt => {
+                "apt-get purge -y $(dpkg-query -W -f '${Package}\n' docker.io docker-ce docker-ce-cli containerd.io 2>/dev/null) && apt-get autoremove -y"
+            }
+            PackageManager::Dnf => "dnf remove -y moby-engine docker-ce docker-ce-cli containerd.io",
+            PackageManager::Yum => "yum remove -y docker-ce docker-ce-cli containerd.io",
+            PackageManager::Apk => "rc-update del docker default; apk del docker",
+            PackageManager::Zypper => "zypper --non-interactive remove docker",
+        };
+        Ok(command.to_string())
+    }
+}
+
+// Sélectionne dans PACKAGE_VERSION la version complète la plus récente du paquet docker qui
+// correspond à `version` ("27.3" accepte 27.3.1). Les versions des paquets sont de la forme
+// 5:27.3.1-1~ubuntu.24.04~noble (apt), 3:27.3.1-1.el9 (dnf, yum) ou 27.3.1_ce-150000.1 (zypper).
+fn package_version(package_manager: PackageManager, version: &str) -> String {
+    let list_command = match package_manager {
+        PackageManager::Apt => "apt-cache madison docker-ce | awk '{print $3}'",
+        PackageManager::Dnf => "dnf list --showduplicates docker-ce | awk '{print $2}'",
+        PackageManager::Yum => "yum list --showduplicates docker-ce | awk '{print $2}'",
+        PackageManager::Zypper => {
+            "zypper --non-interactive --quiet search --details --match-exact docker | awk -F'|' '{print $4}' | tr -d ' '"
+        }
+        // apk sélectionne lui-même la version avec l'opérateur `~`.
+        PackageManager::Apk => return String::new(),
+    };
+    let version = version.replace('.', "\\.");
+    format!(
+        "PACKAGE_VERSION=$({list_command} | grep -E '^([0-9]+:)?{version}[.~_-]' | sort -V | tail -n 1) \
+         && [ -n \"$PACKAGE_VERSION\" ]"
+    )
+}
+
+fn package_manager_tool(package_manager: PackageManager) -> &'static str {
+    match package_manager {
+        PackageManager::Apt => "apt-get",
+        PackageManager::Dnf => "dnf",
+        PackageManager::Yum => "yum",
+        PackageManager::Apk => "apk",
+        PackageManager::Zypper => "zypper",
+    }
+}
+
+// Lit /etc/os-release sur la node.
+pub fn detect_os(session: &dyn RemoteExecutor) -> Result<OsInfo, String> {
+    let output = session.exec("cat /etc/os-release")?;
+    if !output.success() {
+        return Err(format!("Failed to read /etc/os-release : {}", output.stderr));
+    }
+    Ok(OsInfo::parse(&output.stdout))
+}
+
+// Version du moteur docker installée sur la node, None si docker n'est pas installé ou ne
+// répond pas.
+pub fn remote_engine_version(session: &dyn RemoteExecutor) -> Result<Option<String>, String> {
+    let output = session.exec_sudo("docker version --format '{{.Server.Version}}'")?;
+    let version = output.stdout.trim();
+    if output.success() && !version.is_empty() {
+        Ok(Some(version.to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+// Vrai si la version installée correspond à la version demandée ("27.3" accepte "27.3.1").
+pub fn engine_version_matches(installed: &str, expected: &str) -> bool {
+    installed == expected || installed.starts_with(&format!("{}.", expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBIAN: &str = "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nNAME=\"Debian GNU/Linux\"\nVERSION_ID=\"12\"\nVERSION_CODENAME=bookworm\nID=debian\n";
+    const UBUNTU: &str = "PRETTY_NAME=\"Ubuntu 24.04.1 LTS\"\nVERSION_ID=\"24.04\"\nID=ubuntu\nID_LIKE=debian\n";
+    const LINUX_MINT: &str = "NAME=\"Linux Mint\"\nVERSION_ID=\"22\"\nID=linuxmint\nID_LIKE=\"ubuntu debian\"\n";
+    const ROCKY_9: &str = "NAME=\"Rocky Linux\"\nVERSION_ID=\"9.4\"\nID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\nPRETTY_NAME=\"Rocky Linux 9.4 (Blue Onyx)\"\n";
+    const CENTOS_7: &str = "NAME=\"CentOS Linux\"\nVERSION_ID=\"7\"\nID=\"centos\"\nID_LIKE=\"rhel fedora\"\n";
+    const FEDORA: &str = "NAME=\"Fedora Linux\"\nVERSION_ID=40\nID=fedora\n";
+    const ALPINE: &str = "NAME=\"Alpine Linux\"\nID=alpine\nVERSION_ID=3.20.3\nPRETTY_NAME=\"Alpine Linux v3.20\"\n";
+    const OPENSUSE_LEAP: &str = "NAME=\"openSUSE Leap\"\nVERSION_ID=\"15.6\"\nID=\"opensuse-leap\"\nID_LIKE=\"suse opensuse\"\n";
+    const SLES: &str = "NAME=\"SLES\"\nVERSION_ID=\"15.5\"\nID=\"sles\"\nID_LIKE=\"suse\"\n";
+    const ARCH: &str = "NAME=\"Arch Linux\"\nID=arch\nPRETTY_NAME='Arch Linux'\n";
+
+    #[test]
+    fn parse_os_release() {
+        let os_info = OsInfo::parse(ROCKY_9);
+        assert_eq!(os_info.id, "rocky");
+        assert_eq!(os_info.id_like, vec!["rhel", "centos", "fedora"]);
+        assert_eq!(os_info.version_id, "9.4");
+        assert_eq!(os_info.describe(), "Rocky Linux 9.4 (Blue Onyx)");
+        assert_eq!(os_info.bundle_key(), "rocky-9");
+
+        // Quotes simples, lignes vides et commentaires.
+        let os_info = OsInfo::parse(&format!("# os-release\n\n{}", ARCH));
+        assert_eq!(os_info.id, "arch");
+        assert_eq!(os_info.describe(), "Arch Linux");
+
+        let os_info = OsInfo::parse("ID=Debian\nVERSION_ID=12\n");
+        assert_eq!(os_info.id, "debian");
+        assert_eq!(os_info.describe(), "debian 12");
+    }
+
+    #[test]
+    fn bundle_key_of_each_distribution() {
+        let cases = [
+            (DEBIAN, "debian-12"),
+            (UBUNTU, "ubuntu-24.04"),
+            (ROCKY_9, "rocky-9"),
+            (CENTOS_7, "centos-7"),
+            (FEDORA, "fedora-40"),
+            (ALPINE, "alpine-3.20"),
+            (OPENSUSE_LEAP, "opensuse-leap-15.6"),
+        ];
+        for (os_release, bundle_key) in cases {
+            assert_eq!(OsInfo::parse(os_release).bundle_key(), bundle_key);
+        }
+
+        // Le bundle construit depuis rockylinux:9 sert aussi les nodes en 9.5.
+        let rocky_9_5 = ROCKY_9.replace("9.4", "9.5");
+        assert_eq!(OsInfo::parse(&rocky_9_5).bundle_key(), OsInfo::parse(ROCKY_9).bundle_key());
+        let alpine_3_20_1 = ALPINE.replace("3.20.3", "3.20.1");
+        assert_eq!(OsInfo::parse(&alpine_3_20_1).bundle_key(), "alpine-3.20");
+    }
+
+    #[test]
+    fn package_manager_of_each_distribution() {
+        let cases = [
+            (DEBIAN, PackageManager::Apt),
+            (UBUNTU, PackageManager::Apt),
+            (LINUX_MINT, PackageManager::Apt),
+            (ROCKY_9, PackageManager::Dnf),
+            (CENTOS_7, PackageManager::Yum),
+            (FEDORA, PackageManager::Dnf),
+            (ALPINE, PackageManager::Apk),
+            (OPENSUSE_LEAP, PackageManager::Zypper),
+            (SLES, PackageManager::Zypper),
+        ];
+        for (os_release, package_manager) in cases {
+            let os_info = OsInfo::parse(os_release);
+            assert_eq!(os_info.package_manager(), Ok(package_manager), "{}", os_info.describe());
+        }
+    }
+
+    #[test]
+    fn unknown_distribution_is_rejected() {
+        let os_info = OsInfo::parse(ARCH);
+        assert_eq!(os_info.package_manager(), Err(String::from("Unsupported platform : Arch Linux")));
+        assert!(os_info.docker_install_command(None).is_err());
+        assert!(os_info.docker_download_command(None, "/bundle").is_err());
+        assert!(os_info.offline_docker_install_command("/tmp/packages").is_err());
+
+        let os_info = OsInfo::parse("");
+        assert!(os_info.package_manager().is_err());
+    }
+
+    #[test]
+    fn download_command_of_each_distribution() {
+        let cases = [
+            (DEBIAN, None, "apt-get install -y --download-only -o Dir::Cache::archives=/bundle docker.io"),
+            (
+                UBUNTU,
+                Some("27.3"),
+                "https://download.docker.com/linux/ubuntu/gpg",
+            ),
+            (LINUX_MINT, Some("27.3"), "https://download.docker.com/linux/ubuntu "),
+            (
+                ROCKY_9,
+                Some("27.3.1"),
+                "dnf install -y --downloadonly --downloaddir=/bundle docker-ce-$PACKAGE_VERSION docker-ce-cli-$PACKAGE_VERSION",
+            ),
+            (CENTOS_7, None, "yum install -y --downloadonly --downloaddir=/bundle docker-ce docker-ce-cli"),
+            (FEDORA, None, "dnf install -y --downloadonly --destdir /bundle moby-engine"),
+            (ALPINE, Some("27.3"), "apk fetch --recursive --output /bundle 'docker~27.3'"),
+            (OPENSUSE_LEAP, None, "zypper --non-interactive --pkg-cache-dir /bundle install --download-only docker"),
+        ];
+        for (os_release, version, expected) in cases {
+            let os_info = OsInfo::parse(os_release);
+            let command = os_info.docker_download_command(version, "/bundle").unwrap();
+            assert!(command.contains(expected), "{} : {}", os_info.describe(), command);
+        }
+
+        // Le dépôt docker-ce de Fedora n'est pas celui des distributions RHEL.
+        let command = OsInfo::parse(FEDORA).docker_download_command(Some("27.3"), "/bundle").unwrap();
+        assert!(command.contains("https://download.docker.com/linux/fedora/docker-ce.repo"), "{}", command);
+        // Aucun dépôt docker-ce pour zypper, seule la version de la distribution existe.
+        let command = OsInfo::parse(SLES).docker_download_command(Some("27.3"), "/bundle").unwrap();
+        assert!(command.contains("install --download-only docker=$PACKAGE_VERSION"), "{}", command);
+    }
+
+    #[test]
+    fn pinned_install_resolves_the_full_package_version() {
+        let cases = [
+            (DEBIAN, "apt-cache madison docker-ce | awk '{print $3}' | grep -E '^([0-9]+:)?27\\.3[.~_-]'"),
+            (ROCKY_9, "dnf list --showduplicates docker-ce | awk '{print $2}' | grep -E '^([0-9]+:)?27\\.3[.~_-]'"),
+            (CENTOS_7, "yum list --showduplicates docker-ce | awk '{print $2}' | grep -E '^([0-9]+:)?27\\.3[.~_-]'"),
+            (OPENSUSE_LEAP, "search --details --match-exact docker | awk -F'|' '{print $4}' | tr -d ' ' | grep -E '^([0-9]+:)?27\\.3[.~_-]'"),
+        ];
+        for (os_release, expected) in cases {
+            let os_info = OsInfo::parse(os_release);
+            let command = os_info.docker_install_command(Some("27.3")).unwrap();
+            assert!(command.contains(expected), "{} : {}", os_info.describe(), command);
+            assert!(command.contains("[ -n \"$PACKAGE_VERSION\" ]"), "{}", command);
+            assert!(!command.contains("27.3 "), "{}", command);
+        }
+
+        let command = OsInfo::parse(ROCKY_9).docker_install_command(Some("27.3")).unwrap();
+        assert!(command.contains("dnf install -y --allowerasing docker-ce-$PACKAGE_VERSION"), "{}", command);
+        // yum (CentOS 7) n'accepte pas --allowerasing.
+        let command = OsInfo::parse(CENTOS_7).docker_install_command(Some("27.3")).unwrap();
+        assert!(command.contains("yum install -y docker-ce-$PACKAGE_VERSION"), "{}", command);
+        assert!(!command.contains("--allowerasing"), "{}", command);
+    }
+
+    #[test]
+    fn apt_repository_uses_the_ubuntu_codename() {
+        // Linux Mint déclare son propre nom de version, le dépôt docker-ce est celui d'Ubuntu.
+        let command = OsInfo::parse(LINUX_MINT).docker_install_command(Some("27.3")).unwrap();
+        assert!(
+            command.contains("https://download.docker.com/linux/ubuntu $(. /etc/os-release && echo ${UBUNTU_CODENAME:-$VERSION_CODENAME}) stable"),
+            "{}",
+            command
+        );
+    }
+
+    #[test]
+    fn offline_install_command_starts_docker() {
+        let command = OsInfo::parse(ALPINE).offline_docker_install_command("/tmp/packages").unwrap();
+        assert_eq!(
+            command,
+            "apk add --no-network --allow-untrusted /tmp/packages/*.apk && rc-update add docker default && service docker start"
+        );
+        let command = OsInfo::parse(DEBIAN).offline_docker_install_command("/tmp/packages").unwrap();
+        assert!(command.ends_with("&& systemctl enable --now docker"), "{}", command);
+    }
+
+    #[test]
+    fn engine_version_prefixes() {
+        assert!(engine_version_matches("27.3.1", "27.3.1"));
+        assert!(engine_version_matches("27.3.1", "27.3"));
+        assert!(engine_version_matches("27.3.1", "27"));
+        assert!(!engine_version_matches("27.3", "27.3.1"));
+        assert!(!engine_version_matches("27.30.0", "27.3"));
+        assert!(!engine_version_matches("26.1.4", "27"));
+        assert!(!engine_version_matches("", "27.3"));
+    }
+}
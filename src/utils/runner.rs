@@ -0,0 +1,545 @@
+use crate::config::config::NodeConfig;
+use crate::utils::command::{self, CommandOutcome};
+use crate::utils::ssh::{RemoteOutput, RemoteSession, SudoMode};
+use regex::Regex;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Exécution de commandes sur une node distante.
+pub trait RemoteExecutor {
+    fn target(&self) -> &str;
+    fn exec(&self, command: &str) -> Result<RemoteOutput, String>;
+    fn exec_with_stdin(&self, command: &str, stdin: Option<&[u8]>) -> Result<RemoteOutput, String>;
+    // Envoie un fichier local sur l'entrée standard de la commande (archives d'images).
+    fn exec_with_file(&self, command: &str, path: &Path) -> Result<RemoteOutput, String>;
+    fn check_sudo(&self) -> Result<SudoMode, String>;
+    fn exec_sudo(&self, command: &str) -> Result<RemoteOutput, String>;
+}
+
+// Point de passage unique de toutes les commandes, locales ou distantes, lancées par
+// ClusterNoodle. Permet de remplacer l'exécution réelle (tests, simulation).
+pub trait CommandRunner: Send + Sync {
+    // Lance une commande locale en affichant sa sortie au fil de l'eau.
+    fn run(&self, command: Command, timeout: Duration) -> io::Result<CommandOutcome>;
+    // Lance une commande locale dont on ne fait que récupérer la sortie.
+    fn output(&self, command: Command, timeout: Duration) -> io::Result<CommandOutcome>;
+    // Ouvre une session sur une node distante.
+    fn connect(&self, node_config: &NodeConfig) -> Result<Box<dyn RemoteExecutor>, String>;
+}
+
+// Exécution réelle : processus locaux et sessions SSH.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: Command, timeout: Duration) -> io::Result<CommandOutcome> {
+        command::run_with_timeout(command, timeout)
+    }
+
+    fn output(&self, command: Command, timeout: Duration) -> io::Result<CommandOutcome> {
+        command::capture_with_timeout(command, timeout)
+    }
+
+    fn connect(&self, node_config: &NodeConfig) -> Result<Box<dyn RemoteExecutor>, String> {
+        Ok(Box::new(RemoteSession::connect(node_config)?))
+    }
+}
+
+impl RemoteExecutor for RemoteSession {
+    fn target(&self) -> &str {
+        RemoteSession::target(self)
+    }
+
+    fn exec(&self, command: &str) -> Result<RemoteOutput, String> {
+        RemoteSession::exec(self, command)
+    }
+
+    fn exec_with_stdin(&self, command: &str, stdin: Option<&[u8]>) -> Result<RemoteOutput, String> {
+        RemoteSession::exec_with_stdin(self, command, stdin)
+    }
+
+    fn exec_with_file(&self, command: &str, path: &Path) -> Result<RemoteOutput, String> {
+        RemoteSession::exec_with_file(self, command, path)
+    }
+
+    fn check_sudo(&self) -> Result<SudoMode, String> {
+        RemoteSession::check_sudo(self)
+    }
+
+    fn exec_sudo(&self, command: &str) -> Result<RemoteOutput, String> {
+        RemoteSession::exec_sudo(self, command)
+    }
+}
+
+pub fn default_runner() -> Arc<dyn CommandRunner> {
+    Arc::new(SystemRunner)
+}
+
+// Représentation d'une commande locale sous forme de ligne de commande.
+pub fn describe_command(command: &Command) -> String {
+    let mut parts = vec![command.get_program().to_string_lossy().to_string()];
+    parts.extend(command.get_args().map(|arg| arg.to_string_lossy().to_string()));
+    parts.join(" ")
+}
+
+// Machine sur laquelle une commande a été lancée.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Local,
+    Node(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedCommand {
+    pub target: Target,
+    pub command: String,
+    pub sudo: bool,
+}
+
+#[derive(Clone)]
+struct ScriptedResponse {
+    target: Option<Target>,
+    pattern: String,
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+#[derive(Default)]
+struct RecordingState {
+    commands: Vec<RecordedCommand>,
+    responses: Vec<ScriptedResponse>,
+    unreachable_nodes: Vec<String>,
+}
+
+// Implémentation en mémoire qui enregistre la séquence exacte des commandes lancées sans
+// rien exécuter. Les réponses (sortie, code de retour) et les pannes sont injectables.
+#[derive(Clone, Default)]
+pub struct RecordingRunner {
+    state: Arc<Mutex<RecordingState>>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> RecordingRunner {
+        RecordingRunner::default()
+    }
+
+    // Toute commande contenant `pattern` renverra cette réponse. Les réponses les plus
+    // récentes sont prioritaires.
+    pub fn respond(&self, pattern: &str, exit_code: i32, stdout: &str, stderr: &str) {
+        self.push_response(None, pattern, exit_code, stdout, stderr);
+    }
+
+    // Comme `respond`, limité à une machine.
+    pub fn respond_on(&self, target: Target, pattern: &str, exit_code: i32, stdout: &str, stderr: &str) {
+        self.push_response(Some(target), pattern, exit_code, stdout, stderr);
+    }
+
+    // Toute connexion à cette node échouera.
+    #[cfg(test)]
+    pub fn set_unreachable(&self, ip: &str) {
+        self.lock().unreachable_nodes.push(ip.to_string());
+    }
+
+    #[cfg(test)]
+    pub fn recorded(&self) -> Vec<RecordedCommand> {
+        self.lock().commands.clone()
+    }
+
+    #[cfg(test)]
+    pub fn recorded_on(&self, target: &Target) -> Vec<String> {
+        self.lock()
+            .commands
+            .iter()
+            .filter(|recorded| &recorded.target == target)
+            .map(|recorded| recorded.command.clone())
+            .collect()
+    }
+
+    fn push_response(&self, target: Option<Target>, pattern: &str, exit_code: i32, stdout: &str, stderr: &str) {
+        self.lock().responses.push(ScriptedResponse {
+            target,
+            pattern: pattern.to_string(),
+            exit_code,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RecordingState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, target: Target, command: &str, sudo: bool) -> RemoteOutput {
+        let mut state = self.lock();
+        state.commands.push(RecordedCommand {
+            target: target.clone(),
+            command: command.to_string(),
+            sudo,
+        });
+
+        state
+            .responses
+            .iter()
+            .rev()
+            .find(|response| {
+                response.target.as_ref().is_none_or(|expected| expected == &target)
+                    && command.contains(&response.pattern)
+            })
+            .map(|response| RemoteOutput {
+                exit_code: response.exit_code,
+                stdout: response.stdout.clone(),
+                stderr: response.stderr.clone(),
+            })
+            .unwrap_or(RemoteOutput {
+                exit_code: 0,
+                stdout: String::new(),
+                stderr: String::new(),
+            })
+    }
+
+    fn record_local(&self, command: &Command) -> CommandOutcome {
+        let output = self.record(Target::Local, &describe_command(command), false);
+        CommandOutcome {
+            exit_code: Some(output.exit_code),
+            duration: Duration::ZERO,
+            stdout: output.stdout,
+            stderr: output.stderr,
+            timed_out: false,
+            truncated: false,
+        }
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(&self, command: Command, _timeout: Duration) -> io::Result<CommandOutcome> {
+        Ok(self.record_local(&command))
+    }
+
+    fn output(&self, command: Command, _timeout: Duration) -> io::Result<CommandOutcome> {
+        Ok(self.record_local(&command))
+    }
+
+    fn connect(&self, node_config: &NodeConfig) -> Result<Box<dyn RemoteExecutor>, String> {
+        if self.lock().unreachable_nodes.contains(&node_config.ip) {
+            return Err(format!("Failed to connect to {}", node_config.ip));
+        }
+
+        Ok(Box::new(RecordingSession {
+            runner: self.clone(),
+            ip: node_config.ip.clone(),
+            sudo_password: node_config.effective_sudo_password(),
+        }))
+    }
+}
+
+struct RecordingSession {
+    runner: RecordingRunner,
+    ip: String,
+    sudo_password: Option<String>,
+}
+
+impl RemoteExecutor for RecordingSession {
+    fn target(&self) -> &str {
+        &self.ip
+    }
+
+    fn exec(&self, command: &str) -> Result<RemoteOutput, String> {
+        Ok(self.runner.record(Target::Node(self.ip.clone()), command, false))
+    }
+
+    fn exec_with_stdin(&self, command: &str, _stdin: Option<&[u8]>) -> Result<RemoteOutput, String> {
+        self.exec(command)
+    }
+
+    fn exec_with_file(&self, command: &str, _path: &Path) -> Result<RemoteOutput, String> {
+        self.exec(command)
+    }
+
+    // Même enchaînement que RemoteSession::check_sudo : sans sudo sans mot de passe, le
+    // mot de passe déclaré est validé par `sudo -v`.
+    fn check_sudo(&self) -> Result<SudoMode, String> {
+        if self.exec("sudo -n true")?.success() {
+            return Ok(SudoMode::Passwordless);
+        }
+
+        let Some(password) = &self.sudo_password else {
+            return Err(format!(
+                "{} cannot use sudo without a password and no sudo_password is declared",
+                self.ip
+            ));
+        };
+        let output = self.exec_with_stdin("sudo -S -p '' -v", Some(format!("{}\n", password).as_bytes()))?;
+        if output.success() {
+            Ok(SudoMode::Password)
+        } else {
+            Err(format!("{} cannot escalate privileges with sudo : {}", self.ip, output.stderr.trim()))
+        }
+    }
+
+    fn exec_sudo(&self, command: &str) -> Result<RemoteOutput, String> {
+        Ok(self.runner.record(Target::Node(self.ip.clone()), command, true))
+    }
+}
+
+// Simulation (--dry-run) : chaque commande est affichée, secrets masqués, sans être
+// exécutée. Les commandes dont la sortie est exploitée reçoivent une réponse fictive.
+pub struct DryRunRunner {
+    responses: RecordingRunner,
+}
+
+impl DryRunRunner {
+    // Le moteur docker est supposé tourner à la version demandée par le fichier de définition.
+    pub fn new(docker_version: Option<&str>) -> DryRunRunner {
+        let responses = RecordingRunner::new();
+        // Aucun swarm n'est considéré comme existant.
+        responses.respond("docker swarm ca", 1, "", "Error response from daemon: dry-run");
+        responses.respond("usable_ip_adress", 0, "<advertise-address>", "");
+        responses.respond("join-token", 0, "<token>", "");
+        responses.respond("docker version --format", 0, docker_version.unwrap_or("<docker-version>"), "");
+        responses.respond("docker info --format {{.Name}}", 0, "<manager-hostname>", "");
+        // Les images exportées par le manager sont considérées absentes des nodes.
+        responses.respond_on(Target::Local, "docker image inspect", 0, "<image-id>", "");
+        responses.respond("mktemp", 0, "<temp-file>", "");
+        // Les nodes sont supposées tourner sous Debian.
+        responses.respond(
+            "cat /etc/os-release",
+            0,
+            "ID=debian\nVERSION_ID=<version>\nPRETTY_NAME=\"<os-release>\"\n",
+            "",
+        );
+        DryRunRunner { responses }
+    }
+
+    fn print_local(&self, command: &Command) -> CommandOutcome {
+        println!("[dry-run] {}", redact_secrets(&describe_command(command)));
+        self.responses.record_local(command)
+    }
+}
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, command: Command, _timeout: Duration) -> io::Result<CommandOutcome> {
+        Ok(self.print_local(&command))
+    }
+
+    fn output(&self, command: Command, _timeout: Duration) -> io::Result<CommandOutcome> {
+        Ok(self.print_local(&command))
+    }
+
+    fn connect(&self, node_config: &NodeConfig) -> Result<Box<dyn RemoteExecutor>, String> {
+        println!("[dry-run] [{}] ssh {}@{}:{}", node_config.ip, node_config.username, node_config.ip, node_config.port);
+        Ok(Box::new(DryRunSession {
+            session: RecordingSession {
+                runner: self.responses.clone(),
+                ip: node_config.ip.clone(),
+                sudo_password: node_config.effective_sudo_password(),
+            },
+        }))
+    }
+}
+
+struct DryRunSession {
+    session: RecordingSession,
+}
+
+impl RemoteExecutor for DryRunSession {
+    fn target(&self) -> &str {
+        self.session.target()
+    }
+
+    fn exec(&self, command: &str) -> Result<RemoteOutput, String> {
+        println!("[dry-run] [{}] {}", self.target(), redact_secrets(command));
+        self.session.exec(command)
+    }
+
+    fn exec_with_stdin(&self, command: &str, stdin: Option<&[u8]>) -> Result<RemoteOutput, String> {
+        match stdin {
+            Some(_) => println!("[dry-run] [{}] {} < <redacted>", self.target(), redact_secrets(command)),
+            None => println!("[dry-run] [{}] {}", self.target(), redact_secrets(command)),
+        }
+        self.session.exec_with_stdin(command, stdin)
+    }
+
+    fn exec_with_file(&self, command: &str, path: &Path) -> Result<RemoteOutput, String> {
+        println!("[dry-run] [{}] {} < {}", self.target(), redact_secrets(command), path.display());
+        self.session.exec_with_file(command, path)
+    }
+
+    fn check_sudo(&self) -> Result<SudoMode, String> {
+        println!("[dry-run] [{}] sudo -n true", self.target());
+        Ok(SudoMode::Passwordless)
+    }
+
+    fn exec_sudo(&self, command: &str) -> Result<RemoteOutput, String> {
+        println!("[dry-run] [{}] sudo sh -c {}", self.target(), redact_secrets(command));
+        self.session.exec_sudo(command)
+    }
+}
+
+// Masque les secrets (jetons swarm, mots de passe) d'une commande ou d'un fichier avant
+// de l'afficher. La valeur d'une clé secrète est masquée jusqu'à la fin de la ligne : elle
+// peut être entre quotes ou contenir des espaces.
+pub fn redact_secrets(text: &str) -> String {
+    let token = Regex::new(r"(--token\s+)\S+").expect("valid regex");
+    let secret = Regex::new(r"(?i)((?:password|passwd|secret|token)\w*['\x22]?[ \t]*[:=][ \t]*)[^ \t\r\n][^\r\n]*")
+        .expect("valid regex");
+    // Identifiants dans une URL ou une chaîne de connexion : user:motdepasse@hôte
+    let credentials = Regex::new(r"([\w.-]+:)[^\s@/:]+@").expect("valid regex");
+
+    let text = token.replace_all(text, "${1}<redacted>");
+    let text = secret.replace_all(&text, "${1}<redacted>");
+    credentials.replace_all(&text, "${1}<redacted>@").to_string()
+}
+
+// Configuration de cluster lue depuis `cluster_file` dont toutes les commandes passent par
+// `runner`. Le fichier docker-compose est propre à chaque configuration et n'existe pas tant
+// que le test ne l'écrit pas : celui de l'utilisateur n'est jamais lu.
+#[cfg(test)]
+pub fn recording_config(cluster_file: &str, runner: &RecordingRunner) -> crate::ClusterConfig {
+    static CONFIGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    let mut config: crate::ClusterConfig = serde_yaml::from_str(cluster_file).expect("valid cluster file");
+    config.runner = Arc::new(runner.clone());
+    let index = CONFIGS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    config.compose_file_path = std::env::temp_dir()
+        .join(format!("ClusterNoodle-{}-{}-docker-compose.yml", std::process::id(), index))
+        .to_string_lossy()
+        .to_string();
+    config
+}
+
+// Position de la première commande contenant `pattern`, le test échoue si elle n'a pas été
+// exécutée.
+#[cfg(test)]
+pub fn position(commands: &[String], pattern: &str) -> usize {
+    commands
+        .iter()
+        .position(|command| command.contains(pattern))
+        .unwrap_or_else(|| panic!("{} was not run : {:?}", pattern, commands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::config::NodeRole;
+
+    fn node(sudo_password: Option<&str>) -> NodeConfig {
+        let mut node_config = NodeConfig::new("10.0.0.2", "deploy", NodeRole::Worker);
+        node_config.sudo_password = sudo_password.map(String::from);
+        node_config
+    }
+
+    #[test]
+    fn check_sudo_passwordless() {
+        let runner = RecordingRunner::new();
+        let session = runner.connect(&node(None)).unwrap();
+
+        assert!(matches!(session.check_sudo(), Ok(SudoMode::Passwordless)));
+        assert_eq!(runner.recorded_on(&Target::Node(String::from("10.0.0.2"))), vec!["sudo -n true"]);
+    }
+
+    #[test]
+    fn check_sudo_with_password() {
+        let runner = RecordingRunner::new();
+        runner.respond("sudo -n true", 1, "", "sudo: a password is required");
+        let session = runner.connect(&node(Some("hunter2"))).unwrap();
+
+        assert!(matches!(session.check_sudo(), Ok(SudoMode::Password)));
+        let commands = runner.recorded_on(&Target::Node(String::from("10.0.0.2")));
+        assert_eq!(commands, vec!["sudo -n true", "sudo -S -p '' -v"]);
+        // Le mot de passe passe par l'entrée standard, jamais par la commande.
+        assert!(commands.iter().all(|command| !command.contains("hunter2")));
+    }
+
+    #[test]
+    fn check_sudo_falls_back_to_the_ssh_password() {
+        let runner = RecordingRunner::new();
+        runner.respond("sudo -n true", 1, "", "sudo: a password is required");
+        let mut node_config = node(None);
+        node_config.password = String::from("hunter2");
+        let session = runner.connect(&node_config).unwrap();
+
+        assert!(matches!(session.check_sudo(), Ok(SudoMode::Password)));
+        assert_eq!(
+            runner.recorded_on(&Target::Node(String::from("10.0.0.2"))),
+            vec!["sudo -n true", "sudo -S -p '' -v"]
+        );
+    }
+
+    #[test]
+    fn check_sudo_with_wrong_password() {
+        let runner = RecordingRunner::new();
+        runner.respond("sudo -n true", 1, "", "sudo: a password is required");
+        runner.respond("sudo -S", 1, "", "Sorry, try again.");
+        let session = runner.connect(&node(Some("wrong"))).unwrap();
+
+        let error = session.check_sudo().err().unwrap();
+        assert!(error.contains("cannot escalate privileges"), "{}", error);
+    }
+
+    #[test]
+    fn check_sudo_without_password() {
+        let runner = RecordingRunner::new();
+        runner.respond("sudo -n true", 1, "", "sudo: a password is required");
+        let session = runner.connect(&node(None)).unwrap();
+
+        let error = session.check_sudo().err().unwrap();
+        assert!(error.contains("no sudo_password is declared"), "{}", error);
+        assert_eq!(runner.recorded().len(), 1);
+    }
+
+    #[test]
+    fn unreachable_node() {
+        let runner = RecordingRunner::new();
+        runner.set_unreachable("10.0.0.2");
+
+        assert!(runner.connect(&node(None)).is_err());
+        assert!(runner.recorded().is_empty());
+    }
+
+    #[test]
+    fn responses_are_scoped_to_their_target() {
+        let runner = RecordingRunner::new();
+        runner.respond_on(Target::Node(String::from("10.0.0.3")), "docker info", 0, "active", "");
+        let session = runner.connect(&node(None)).unwrap();
+
+        assert_eq!(session.exec("docker info").unwrap().stdout, "");
+        assert_eq!(runner.recorded()[0].target, Target::Node(String::from("10.0.0.2")));
+    }
+
+    #[test]
+    fn redact_join_token() {
+        assert_eq!(
+            redact_secrets("docker swarm join --token SWMTKN-1-abc 10.0.0.1:2377"),
+            "docker swarm join --token <redacted> 10.0.0.1:2377"
+        );
+    }
+
+    #[test]
+    fn redact_quoted_passwords() {
+        let compose = "environment:\n  MYSQL_ROOT_PASSWORD: '123456'\n  POSTGRES_PASSWORD: 'true'\n  \"API_SECRET\": \"s3cr3t\"\n  MYSQL_DATABASE: app\n";
+        assert_eq!(
+            redact_secrets(compose),
+            "environment:\n  MYSQL_ROOT_PASSWORD: <redacted>\n  POSTGRES_PASSWORD: <redacted>\n  \"API_SECRET\": <redacted>\n  MYSQL_DATABASE: app\n"
+        );
+    }
+
+    #[test]
+    fn redact_unquoted_multi_word_password() {
+        assert_eq!(redact_secrets("DB_PASSWORD: abc def\nDB_USER: app"), "DB_PASSWORD: <redacted>\nDB_USER: app");
+        assert_eq!(redact_secrets("DB_PASSWORD=abc def"), "DB_PASSWORD=<redacted>");
+        // Une clé sans valeur sur sa ligne ne masque pas la ligne suivante.
+        assert_eq!(redact_secrets("passwords:\n  - app\n"), "passwords:\n  - app\n");
+    }
+
+    #[test]
+    fn redact_url_credentials() {
+        assert_eq!(
+            redact_secrets("DATABASE_URL: mysql://app:hunter2@db:3306/app"),
+            "DATABASE_URL: mysql://app:<redacted>@db:3306/app"
+        );
+        assert_eq!(redact_secrets("git clone https://github.com/org/repo"), "git clone https://github.com/org/repo");
+    }
+}
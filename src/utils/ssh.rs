@@ -1,194 +1,655 @@
 #![allow(dead_code)]
 use crate::config::config;
 use crate::config::config::NodeConfig;
-use crate::utils::command;
-use std::path::PathBuf;
-use std::process::Command;
-use std::time::Duration;
-
-pub fn generate_ssh_key() {
-    // Resolve ~/.ssh path
-    let mut key_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-    key_path.push(".ssh/cluster_noodle");
-
-    // Convert to string path
-    let key_path_str = key_path.to_str().unwrap();
-
-    // Run ssh-keygen with arguments
-    let output = Command::new("ssh-keygen")
-        .args(&["-t", "rsa", "-b", "4096", "-f", key_path_str, "-N", ""])
-        .output()
-        .expect(&format!(
-            "Failed to execute ssh-keygen for in the following path : {}",
-            key_path_str
-        ));
+use crate::utils::envVariables::EnvVariables;
+use crate::config::state::NodeFacts;
+use crate::docker::bundle::Bundle;
+use crate::utils::platform::{OsInfo, detect_os, engine_version_matches, remote_engine_version};
+use crate::utils::runner::RemoteExecutor;
+use crate::utils::parallel::{NodeResult, print_report, run_on_nodes};
+use crate::utils::identity::{SshIdentity, get_default_identity_path, read_public_key, resolve_identity};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use ssh2::{Channel, CheckResult, HashType, KnownHostFileKind, KnownHostKeyFormat, Session};
+use std::fs;
+use std::cell::OnceCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-    if !output.status.success() {
-        println!(
-            "Erreur de génération de clé : {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1000);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Sérialise les accès au fichier known_hosts de ClusterNoodle.
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+// Résultat d'une commande exécutée sur une node distante.
+pub struct RemoteOutput {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
 }
 
-pub fn check_existing_ssh_key() -> bool {
-    let mut ssh_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/"));
-    ssh_dir.push(".ssh");
+impl RemoteOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
 
-    let private_key = ssh_dir.join("cluster_noodle");
-    let public_key = ssh_dir.join("cluster_noodle.pub");
+// Manière dont l'utilisateur SSH peut obtenir les droits root sur la node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SudoMode {
+    Passwordless,
+    Password,
+}
 
-    private_key.exists() && public_key.exists()
+// Session SSH ouverte sur une node, réutilisée pour toutes les commandes à y exécuter.
+pub struct RemoteSession {
+    session: Session,
+    target: String,
+    sudo_password: Option<String>,
+    sudo_mode: OnceCell<SudoMode>,
 }
 
-pub fn copy_ssh_key_to_machines(config: &config::ClusterConfig) {
-    for node_config in &config.nodes_configs {
-        let target = format!("{}@{}", node_config.username, node_config.ip);
+impl RemoteSession {
+    pub fn connect(node_config: &NodeConfig) -> Result<RemoteSession, String> {
+        let target = format!("{}@{}:{}", node_config.username, node_config.ip, node_config.port);
 
-        let mut cmd = Command::new("ssh-copy-id");
-        cmd.arg(&target);
+        let address = (node_config.ip.as_str(), node_config.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid address for {} : {}", target, e))?
+            .next()
+            .ok_or_else(|| format!("No address found for {}", target))?;
 
-        match command::run_with_timeout(cmd, Duration::from_secs(5)) {
-            Ok(Some(output)) => {
-                if !output.status.success() {
-                    println!(
-                        "ssh-copy-id failed: {}",
-                        String::from_utf8_lossy(&output.stderr)
-                    );
-                }
+        let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+            .map_err(|e| format!("Failed to connect to {} : {}", target, e))?;
+
+        let mut session =
+            Session::new().map_err(|e| format!("Failed to create ssh session : {}", e))?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(COMMAND_TIMEOUT.as_millis() as u32);
+        session
+            .handshake()
+            .map_err(|e| format!("SSH handshake failed with {} : {}", target, e))?;
+
+        verify_host_key(&session, node_config)?;
+
+        authenticate(&session, node_config);
+        if !session.authenticated() {
+            return Err(format!("SSH authentication failed for {}", target));
+        }
+
+        let sudo_password = node_config.effective_sudo_password();
+
+        Ok(RemoteSession {
+            session,
+            target,
+            sudo_password,
+            sudo_mode: OnceCell::new(),
+        })
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn exec(&self, command: &str) -> Result<RemoteOutput, String> {
+        self.exec_with_stdin(command, None)
+    }
+
+    // Vérifie que l'utilisateur peut passer root, sans mot de passe ou avec celui de la node.
+    pub fn check_sudo(&self) -> Result<SudoMode, String> {
+        if let Some(sudo_mode) = self.sudo_mode.get() {
+            return Ok(sudo_mode.clone());
+        }
+
+        let sudo_mode = if self.exec("sudo -n true")?.success() {
+            SudoMode::Passwordless
+        } else {
+            let password = self.sudo_password.as_ref().ok_or_else(|| {
+                format!(
+                    "{} cannot use sudo without a password and no sudo_password is declared",
+                    self.target
+                )
+            })?;
+            let output =
+                self.exec_with_stdin("sudo -S -p '' -v", Some(format!("{}\n", password).as_bytes()))?;
+            if !output.success() {
+                return Err(format!(
+                    "{} cannot escalate privileges with sudo : {}",
+                    self.target,
+                    output.stderr.trim()
+                ));
             }
-            Ok(None) => {
-                println!("ssh-copy-id timed out after 5 seconds for {}", node_config.ip);
+            SudoMode::Password
+        };
+
+        let _ = self.sudo_mode.set(sudo_mode.clone());
+        Ok(sudo_mode)
+    }
+
+    // Exécute une commande en root. Le mot de passe sudo est envoyé sur l'entrée standard
+    // du channel SSH, il n'apparaît ni dans la commande distante ni dans les processus locaux.
+    // `-k` force sudo à lire le mot de passe même si des identifiants sont en cache.
+    pub fn exec_sudo(&self, command: &str) -> Result<RemoteOutput, String> {
+        match self.check_sudo()? {
+            SudoMode::Passwordless => {
+                self.exec(&format!("sudo -n sh -c {}", shell_quote(command)))
             }
-            Err(e) => {
-                println!("Failed to execute: {}", e);
+            SudoMode::Password => {
+                let password = self.sudo_password.clone().unwrap_or_default();
+                self.exec_with_stdin(
+                    &format!("sudo -k -S -p '' sh -c {}", shell_quote(command)),
+                    Some(format!("{}\n", password).as_bytes()),
+                )
             }
         }
     }
-}
 
-fn check_and_install_tools(node_config: &NodeConfig) {
-    let target = format!("{}@{}", node_config.username, node_config.ip);
+    pub fn exec_with_stdin(&self, command: &str, stdin: Option<&[u8]>) -> Result<RemoteOutput, String> {
+        match stdin {
+            Some(mut input) => self.exec_with_input(command, Some(&mut input)),
+            None => self.exec_with_input(command, None),
+        }
+    }
+
+    // Exécute une commande en lui envoyant le contenu d'un fichier local sur son entrée
+    // standard, par blocs : le fichier n'est jamais chargé entièrement en mémoire.
+    pub fn exec_with_file(&self, command: &str, path: &Path) -> Result<RemoteOutput, String> {
+        let mut file = fs::File::open(path)
+            .map_err(|e| format!("Failed to open {} : {}", path.display(), e))?;
+        self.exec_with_input(command, Some(&mut file))
+    }
+
+    fn exec_with_input(&self, command: &str, input: Option<&mut dyn Read>) -> Result<RemoteOutput, String> {
+        let mut channel = self
+            .session
+            .channel_session()
+            .map_err(|e| format!("Failed to open a channel on {} : {}", self.target, e))?;
 
-    // Check if Docker is installed
-    let mut docker_check = Command::new("sshpass");
-    docker_check
-        .arg("-p")
-        .arg(&node_config.password)
-        .arg("ssh")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg(&target)
-        .arg("command -v docker");
+        channel
+            .exec(command)
+            .map_err(|e| format!("Failed to execute command on {} : {}", self.target, e))?;
 
-    // Run command to check if docker is installed
-    match command::run_with_timeout(docker_check, Duration::from_secs(100)) {
-        Ok(Some(output)) => {
-            if output.status.success() {
-                println!("Docker is already installed on {}", node_config.ip);
+        self.session.set_blocking(false);
+        let streams = self.pump_channel(&mut channel, input);
+        self.session.set_blocking(true);
+        let (stdout, stderr) = streams?;
+
+        channel
+            .wait_close()
+            .map_err(|e| format!("Failed to close channel on {} : {}", self.target, e))?;
+
+        let exit_code = channel
+            .exit_status()
+            .map_err(|e| format!("Failed to get exit status on {} : {}", self.target, e))?;
+
+        Ok(RemoteOutput {
+            exit_code,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+
+    // Envoie l'entrée standard et lit stdout et stderr en alternance, la session étant en mode
+    // non bloquant. Lire un flux jusqu'au bout avant l'autre bloquerait la commande dès que la
+    // fenêtre SSH serait remplie par le flux qui n'est pas lu.
+    fn pump_channel(
+        &self,
+        channel: &mut Channel,
+        mut input: Option<&mut dyn Read>,
+    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut pending = Vec::new();
+        let mut eof_sent = false;
+        let mut buffer = vec![0; 32 * 1024];
+        let mut last_activity = Instant::now();
+
+        loop {
+            let mut progress = false;
+
+            if !eof_sent {
+                if pending.is_empty()
+                    && let Some(reader) = input.as_mut()
+                {
+                    let read = reader
+                        .read(&mut buffer)
+                        .map_err(|e| format!("Failed to read stdin for {} : {}", self.target, e))?;
+                    if read == 0 {
+                        input = None;
+                    }
+                    pending.extend_from_slice(&buffer[..read]);
+                }
+
+                if !pending.is_empty() {
+                    match channel.write(&pending) {
+                        Ok(written) => {
+                            pending.drain(..written);
+                            progress = true;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => {
+                            return Err(format!("Failed to write to stdin on {} : {}", self.target, e));
+                        }
+                    }
+                } else if input.is_none() {
+                    match channel.send_eof().map_err(io::Error::from) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        _ => {
+                            eof_sent = true;
+                            progress = true;
+                        }
+                    }
+                }
+            }
+
+            let mut drained = true;
+            for (name, stream_id, output) in [("stdout", 0, &mut stdout), ("stderr", 1, &mut stderr)] {
+                match channel.stream(stream_id).read(&mut buffer) {
+                    Ok(0) => (),
+                    Ok(read) => {
+                        output.extend_from_slice(&buffer[..read]);
+                        drained = false;
+                        progress = true;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => drained = false,
+                    Err(e) => return Err(format!("Failed to read {} on {} : {}", name, self.target, e)),
+                }
+            }
+            // La commande peut se terminer sans avoir lu toute son entrée standard.
+            if drained && channel.eof() {
+                return Ok((stdout, stderr));
+            }
+
+            if progress {
+                last_activity = Instant::now();
+            } else if last_activity.elapsed() > COMMAND_TIMEOUT {
+                return Err(format!("Command timed out on {}", self.target));
             } else {
-                println!("Docker is not installed on {}. Installing...", node_config.ip);
-                install_docker(node_config, &target);
+                thread::sleep(POLL_INTERVAL);
             }
         }
-        Ok(None) => println!("Timeout while checking Docker installation on {}", node_config.ip),
-        Err(e) => {
-            println!("Error checking Docker installation on {}: {}", node_config.ip, e);
-            install_docker(node_config, &target);
+    }
+}
+
+// Entoure une chaîne de quotes simples pour la passer telle quelle à un shell distant.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Vérifie la clé d'hôte présentée par la node : l'empreinte déclarée dans le fichier
+// de configuration fait foi, sinon la clé est épinglée à la première connexion (TOFU).
+fn verify_host_key(session: &Session, node_config: &NodeConfig) -> Result<(), String> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| format!("No host key presented by {}", node_config.ip))?;
+    let fingerprint = get_host_key_fingerprint(session)?;
+
+    if let Some(expected) = &node_config.host_key_fingerprint
+        && expected.trim() != fingerprint
+    {
+        return Err(format!(
+            "Host key mismatch for {} : expected {} but the node presented {}",
+            node_config.ip,
+            expected.trim(),
+            fingerprint
+        ));
+    }
+
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let env = EnvVariables {};
+    let known_hosts_path = PathBuf::from(env.get_known_hosts_file_path());
+
+    let mut known_hosts = session
+        .known_hosts()
+        .map_err(|e| format!("Failed to init known hosts : {}", e))?;
+    if known_hosts_path.exists() {
+        known_hosts
+            .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Failed to read {} : {}", known_hosts_path.display(), e))?;
+    }
+
+    match known_hosts.check_port(&node_config.ip, node_config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => {
+            println!(
+                "Pinning host key of {} ({}) in {}",
+                node_config.ip,
+                fingerprint,
+                known_hosts_path.display()
+            );
+            known_hosts
+                .add(
+                    &get_known_host_name(node_config),
+                    key,
+                    "ClusterNoodle",
+                    KnownHostKeyFormat::from(key_type),
+                )
+                .map_err(|e| format!("Failed to pin host key of {} : {}", node_config.ip, e))?;
+            write_known_hosts(&known_hosts, &known_hosts_path)
         }
+        CheckResult::Mismatch => Err(format!(
+            "Host key of {} ({}) does not match the one pinned in {}. Aborting, the node may have been reinstalled or the connection intercepted.",
+            node_config.ip,
+            fingerprint,
+            known_hosts_path.display()
+        )),
+        CheckResult::Failure => Err(format!(
+            "Failed to check host key of {} against {}",
+            node_config.ip,
+            known_hosts_path.display()
+        )),
     }
+}
 
-    // Check if sshpass is installed
-    let mut sshpass_check = Command::new("sshpass");
-    sshpass_check
-        .arg("-p")
-        .arg(&node_config.password)
-        .arg("ssh")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg(&target)
-        .arg("command -v sshpass");
-
-    // Run command to check if sshpass is installed
-    match command::run_with_timeout(sshpass_check, Duration::from_secs(100)) {
-        Ok(Some(output)) => {
-            if output.status.success() {
-                println!("sshpass is already installed on {}", node_config.ip);
-            } else {
-                println!("sshpass is not installed on {}. Installing...", node_config.ip);
-                install_sshpass(node_config, &target);
-            }
+// Empreinte au format affiché par OpenSSH (`ssh-keygen -lf`).
+fn get_host_key_fingerprint(session: &Session) -> Result<String, String> {
+    let hash = session
+        .host_key_hash(HashType::Sha256)
+        .ok_or_else(|| String::from("Failed to hash the host key"))?;
+    Ok(format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+}
+
+fn get_known_host_name(node_config: &NodeConfig) -> String {
+    if node_config.port == 22 {
+        node_config.ip.clone()
+    } else {
+        format!("[{}]:{}", node_config.ip, node_config.port)
+    }
+}
+
+fn write_known_hosts(known_hosts: &ssh2::KnownHosts, path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    known_hosts
+        .write_file(path, KnownHostFileKind::OpenSSH)
+        .map_err(|e| format!("Failed to write {} : {}", path.display(), e))
+}
+
+// On s'authentifie avec l'identité de la node, le mot de passe ne sert qu'en dernier
+// recours (par exemple avant que la clé publique n'ait été installée).
+fn authenticate(session: &Session, node_config: &NodeConfig) {
+    match resolve_identity(node_config) {
+        SshIdentity::Agent => {
+            let _ = session.userauth_agent(&node_config.username);
         }
-        Ok(None) => println!("Timeout while checking sshpass installation on {}", node_config.ip),
-        Err(e) => {
-            println!("Error checking sshpass installation on {}: {}", node_config.ip, e);
-            install_sshpass(node_config, &target);
+        SshIdentity::KeyFile(private_key) => {
+            if private_key.exists() {
+                let _ = session.userauth_pubkey_file(&node_config.username, None, &private_key, None);
+            }
         }
     }
+
+    if !session.authenticated() && !node_config.password.is_empty() {
+        let _ = session.userauth_password(&node_config.username, &node_config.password);
+    }
+}
+
+pub fn copy_ssh_key_to_machines(config: &config::ClusterConfig, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+    let results = run_on_nodes(nodes, config.parallelism, |node_config| {
+        let identity = resolve_identity(node_config);
+        // En simulation la clé n'a pas forcément été générée.
+        let public_key = match read_public_key(&identity) {
+            Err(_) if config.dry_run => Some(format!("<public key of {}>", identity.describe())),
+            public_key => public_key?,
+        };
+        let Some(public_key) = public_key else {
+            return Ok(format!("Using {}, no key to install", identity.describe()));
+        };
+
+        let session = config.runner.connect(node_config)?;
+
+        // On n'ajoute la clé que si elle n'est pas déjà autorisée sur la node.
+        let command = format!(
+            "mkdir -p ~/.ssh && chmod 700 ~/.ssh && touch ~/.ssh/authorized_keys && chmod 600 ~/.ssh/authorized_keys && (grep -qxF {key} ~/.ssh/authorized_keys || echo {key} >> ~/.ssh/authorized_keys)",
+            key = shell_quote(&public_key)
+        );
+
+        let output = session.exec(&command)?;
+        if output.success() {
+            Ok(format!("{} authorized", identity.describe()))
+        } else {
+            Err(format!("Failed to copy ssh key : {}", output.stderr))
+        }
+    });
+
+    print_report("SSH key installation", &results);
+    results
+}
+
+// Retire de chaque node la clé générée par ClusterNoodle (~/.ssh/cluster_noodle). Les clés
+// propres aux nodes (identity_file) et l'agent SSH appartiennent à l'utilisateur, elles
+// restent autorisées.
+pub fn remove_ssh_key_from_machines(config: &config::ClusterConfig, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+    let generated_key_path = get_default_identity_path();
+
+    let results = run_on_nodes(nodes, config.parallelism, |node_config| {
+        let identity = resolve_identity(node_config);
+        match &identity {
+            SshIdentity::KeyFile(path) if *path == generated_key_path => (),
+            _ => return Ok(format!("Using {}, no key to remove", identity.describe())),
+        }
+        let public_key = match read_public_key(&identity) {
+            Err(_) if config.dry_run => format!("<public key of {}>", identity.describe()),
+            public_key => public_key?.unwrap_or_default(),
+        };
+
+        let session = config.runner.connect(node_config)?;
+
+        // grep renvoie 1 quand il ne reste aucune ligne, ce qui n'est pas une erreur. Au-delà
+        // (fichier illisible...), authorized_keys n'est pas remplacé par une copie incomplète.
+        let command = format!(
+            "if [ -f ~/.ssh/authorized_keys ]; then grep -vxF {key} ~/.ssh/authorized_keys > ~/.ssh/authorized_keys.tmp; status=$?; if [ $status -gt 1 ]; then rm -f ~/.ssh/authorized_keys.tmp; exit $status; fi; mv ~/.ssh/authorized_keys.tmp ~/.ssh/authorized_keys && chmod 600 ~/.ssh/authorized_keys; fi",
+            key = shell_quote(&public_key)
+        );
+
+        let output = session.exec(&command)?;
+        if output.success() {
+            Ok(format!("{} removed", identity.describe()))
+        } else {
+            Err(format!("Failed to remove ssh key : {}", output.stderr))
+        }
+    });
+
+    print_report("SSH key removal", &results);
+    results
 }
 
-fn install_docker(node_config: &NodeConfig, target: &str) {
-    let mut cmd = Command::new("sshpass");
-    cmd.arg("-p")
-        .arg(&node_config.password)
-        .arg("ssh")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg(target)
-        .arg(format!(
-            "echo {} | sudo -S apt-get update -y && echo {} | sudo -S apt-get install -y docker.io",
-            node_config.password, node_config.password
+// Outils utilisés par les commandes lancées sur les nodes, avec le paquet qui les fournit.
+const REQUIRED_TOOLS: [(&str, &str); 3] = [("curl", "curl"), ("grep", "grep"), ("xargs", "findutils")];
+
+// Vérification préalable d'une node : outils nécessaires, docker (dans la version
+// `docker_version` si elle est renseignée), démon démarré et utilisateur membre du groupe
+// docker. Seul ce qui manque est installé ou corrigé.
+pub fn check_and_install_tools(config: &config::ClusterConfig, node_config: &NodeConfig) -> Result<NodeFacts, String> {
+    let session = config.runner.connect(node_config)?;
+    session.check_sudo()?;
+
+    let os_info = detect_os(session.as_ref())?;
+    let mut facts = NodeFacts {
+        os: os_info.describe(),
+        ..NodeFacts::default()
+    };
+
+    // curl ne sert qu'à installer docker depuis ses dépôts, inutile avec un bundle.
+    let missing_packages: Vec<&str> = REQUIRED_TOOLS
+        .iter()
+        .filter(|(tool, _)| config.bundle.is_none() || *tool != "curl")
+        .filter(|(tool, _)| !has_command(session.as_ref(), tool))
+        .map(|(_, package)| *package)
+        .collect();
+    if !missing_packages.is_empty() && config.bundle.is_some() {
+        return Err(format!(
+            "{} missing, it cannot be installed without network access",
+            missing_packages.join(", ")
         ));
+    }
+    if !missing_packages.is_empty() {
+        run_sudo(session.as_ref(), &os_info.install_packages_command(&missing_packages)?)?;
+        facts.installed.extend(missing_packages.iter().map(|package| package.to_string()));
+    }
 
-    match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-        Ok(Some(output)) => {
-            if output.status.success() {
-                println!("Docker installed on {}", node_config.ip);
-            } else {
-                println!(
-                    "Docker installation failed on {}: {}",
-                    node_config.ip,
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            }
+    let expected_version = config.docker_version.as_deref();
+    let mut docker_version = remote_engine_version(session.as_ref())?;
+    let docker_present = has_command(session.as_ref(), "docker");
+    let wrong_version = match (expected_version, &docker_version) {
+        (Some(expected_version), Some(version)) => !engine_version_matches(version, expected_version),
+        _ => false,
+    };
+    if !docker_present || wrong_version {
+        match &config.bundle {
+            Some(bundle) => install_docker_from_bundle(session.as_ref(), &os_info, bundle)?,
+            None => run_sudo(session.as_ref(), &os_info.docker_install_command(expected_version)?)?,
         }
-        Ok(None) => println!("Timeout during Docker installation on {}", node_config.ip),
-        Err(e) => println!("Docker installation failed on {}: {}", node_config.ip, e),
-    }
-}
-
-fn install_sshpass(node_config: &NodeConfig, target: &str) {
-    let mut cmd = Command::new("sshpass");
-    cmd.arg("-p")
-        .arg(&node_config.password)
-        .arg("ssh")
-        .arg("-o")
-        .arg("StrictHostKeyChecking=no")
-        .arg(target)
-        .arg(format!(
-            "echo {} | sudo -S apt-get update -y && echo {} | sudo -S apt-get install -y sshpass",
-            node_config.password, node_config.password
+        facts.installed.push(String::from("docker"));
+        docker_version = remote_engine_version(session.as_ref())?;
+    }
+
+    // Docker est installé mais son démon ne répond pas.
+    if docker_version.is_none() {
+        run_sudo(session.as_ref(), os_info.docker_start_command()?)?;
+        docker_version = remote_engine_version(session.as_ref())?;
+    }
+    facts.docker_running = docker_version.is_some();
+    if !facts.docker_running {
+        return Err(String::from("The docker daemon is not running"));
+    }
+    if let (Some(expected_version), Some(version)) = (expected_version, &docker_version)
+        && !engine_version_matches(version, expected_version)
+    {
+        return Err(format!("Docker {} installed instead of {}", version, expected_version));
+    }
+    facts.docker_version = docker_version;
+
+    // Le groupe est pris en compte aux connexions suivantes.
+    let groups = session.exec("id -nG")?;
+    if !groups.stdout.split_whitespace().any(|group| group == "docker") {
+        run_sudo(
+            session.as_ref(),
+            &os_info.add_user_to_group_command(&node_config.username, "docker")?,
+        )?;
+    }
+    facts.docker_group = true;
+
+    Ok(facts)
+}
+
+// Copie les paquets docker du bundle correspondant à la distribution de la node puis les
+// installe sans accès au réseau.
+fn install_docker_from_bundle(session: &dyn RemoteExecutor, os_info: &OsInfo, bundle: &Bundle) -> Result<(), String> {
+    let Some(packages_folder) = bundle.packages_folder(os_info) else {
+        return Err(format!(
+            "The bundle has no docker packages for {} ({}), create it with --distro",
+            os_info.describe(),
+            os_info.bundle_key()
         ));
+    };
 
-    match command::run_with_timeout(cmd, Duration::from_secs(1000)) {
-        Ok(Some(output)) => {
-            if output.status.success() {
-                println!("sshpass installed on {}", node_config.ip);
-            } else {
-                println!(
-                    "sshpass installation failed on {}: {}",
-                    node_config.ip,
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            }
+    let remote_folder = "/tmp/ClusterNoodle-packages";
+    session.exec(&format!("rm -rf {0} && mkdir -p {0}", remote_folder))?;
+
+    let mut packages: Vec<PathBuf> = fs::read_dir(&packages_folder)
+        .map_err(|e| format!("Failed to read {} : {}", packages_folder.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    packages.sort();
+    for package in &packages {
+        let file_name = package.file_name().unwrap_or_default().to_string_lossy();
+        let output = session.exec_with_file(
+            &format!("cat > {}/{}", remote_folder, shell_quote(&file_name)),
+            package,
+        )?;
+        if !output.success() {
+            return Err(format!("Failed to copy {} : {}", file_name, output.stderr.trim()));
         }
-        Ok(None) => println!("Timeout during sshpass installation on {}", node_config.ip),
-        Err(e) => println!("sshpass installation failed on {}: {}", node_config.ip, e),
+    }
+
+    let result = run_sudo(session, &os_info.offline_docker_install_command(remote_folder)?);
+    let _ = session.exec(&format!("rm -rf {}", remote_folder));
+    result
+}
+
+fn has_command(session: &dyn RemoteExecutor, command: &str) -> bool {
+    session
+        .exec(&format!("command -v {}", command))
+        .is_ok_and(|output| output.success())
+}
+
+fn run_sudo(session: &dyn RemoteExecutor, command: &str) -> Result<(), String> {
+    let output = session.exec_sudo(command)?;
+    if output.success() {
+        Ok(())
+    } else {
+        Err(format!("{} failed : {}", command, output.stderr.trim()))
     }
 }
 
-pub fn join_cluster(config: &config::ClusterConfig) {
-    for node_config in &config.nodes_configs {
-        check_and_install_tools(node_config);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, recording_config};
+
+    const CLUSTER_FILE: &str = "
+version: 1
+nodes:
+  - ip: 10.0.0.2
+    username: deploy
+  - ip: 10.0.0.3
+    username: deploy
+    identity_file: ~/.ssh/id_ed25519
+  - ip: 10.0.0.4
+    username: deploy
+    ssh_agent: true
+";
+
+    #[test]
+    fn start_quotes_the_public_key() {
+        let folder = std::env::temp_dir().join(format!("cluster_noodle_key_{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let private_key = folder.join("id_ed25519");
+        fs::write(folder.join("id_ed25519.pub"), "ssh-ed25519 AAAAC3Nz deploy's key\n").unwrap();
+
+        let runner = RecordingRunner::new();
+        let config = recording_config(
+            &format!("version: 1\nnodes:\n  - ip: 10.0.0.2\n    username: deploy\n    identity_file: {}\n", private_key.display()),
+            &runner,
+        );
+        let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+
+        let results = copy_ssh_key_to_machines(&config, &nodes);
+        fs::remove_dir_all(&folder).unwrap();
+        assert!(results.iter().all(|result| result.result.is_ok()));
+
+        let commands = runner.recorded_on(&Target::Node(String::from("10.0.0.2")));
+        assert_eq!(commands.len(), 1);
+        let quoted_key = shell_quote("ssh-ed25519 AAAAC3Nz deploy's key");
+        assert!(commands[0].contains(&format!("grep -qxF {} ", quoted_key)), "{}", commands[0]);
+        assert!(commands[0].contains(&format!("echo {} >>", quoted_key)), "{}", commands[0]);
+    }
+
+    #[test]
+    fn stop_only_removes_the_generated_key() {
+        let runner = RecordingRunner::new();
+        let mut config = recording_config(CLUSTER_FILE, &runner);
+        // La clé générée n'existe pas forcément sur la machine qui lance les tests.
+        config.dry_run = true;
+        let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+
+        let results = remove_ssh_key_from_machines(&config, &nodes);
+        assert!(results.iter().all(|result| result.result.is_ok()));
+
+        let commands = runner.recorded_on(&Target::Node(String::from("10.0.0.2")));
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].contains("grep -vxF '"), "{}", commands[0]);
+        assert!(commands[0].contains("if [ $status -gt 1 ]"), "{}", commands[0]);
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.3"))).is_empty());
+        assert!(runner.recorded_on(&Target::Node(String::from("10.0.0.4"))).is_empty());
     }
 }
@@ -0,0 +1,354 @@
+use crate::ClusterConfig;
+use crate::config::config::NodeConfig;
+use crate::utils::parallel::{NodeResult, print_report, run_on_nodes};
+use crate::utils::runner::RemoteExecutor;
+use crate::utils::ssh::shell_quote;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// Fichier local à copier sur les nodes.
+struct LocalFile {
+    path: PathBuf,
+    hash: String,
+    mode: u32,
+}
+
+// Dossier à reproduire à l'identique sur les nodes, ou fichiers isolés d'un dossier qui ne
+// sont copiés que s'ils diffèrent. Pour un dossier, les fichiers absents en local sont
+// supprimés des nodes.
+struct SyncRoot {
+    root: PathBuf,
+    // Fichiers, par chemin relatif à `root`.
+    files: BTreeMap<String, LocalFile>,
+    is_folder: bool,
+}
+
+// Copie le dossier du projet et le fichier de configuration du serveur au même chemin sur
+// chaque node, pour que les services qui les montent puissent tourner partout. Seuls les
+// fichiers modifiés depuis la dernière copie sont transférés.
+pub fn sync_project(config: &ClusterConfig, nodes: &[&NodeConfig]) -> Vec<NodeResult> {
+    let sync_roots = match local_sync_roots(config) {
+        Ok(sync_roots) => sync_roots,
+        Err(e) => {
+            let results: Vec<NodeResult> = nodes
+                .iter()
+                .map(|node_config| NodeResult {
+                    ip: node_config.ip.clone(),
+                    result: Err(e.clone()),
+                })
+                .collect();
+            print_report("Project sync", &results);
+            return results;
+        }
+    };
+
+    let results = run_on_nodes(nodes, config.parallelism, |node_config| {
+        let session = config.runner.connect(node_config)?;
+        let mut copied = 0;
+        let mut removed = 0;
+        let mut unchanged = 0;
+        for sync_root in &sync_roots {
+            let (root_copied, root_removed, root_unchanged) =
+                sync_root_to_node(session.as_ref(), sync_root, &node_config.username)?;
+            copied += root_copied;
+            removed += root_removed;
+            unchanged += root_unchanged;
+        }
+        Ok(format!(
+            "{} file(s) copied, {} removed, {} unchanged",
+            copied, removed, unchanged
+        ))
+    });
+
+    print_report("Project sync", &results);
+    results
+}
+
+// Chemins à synchroniser : le dossier du projet et la configuration nginx ou apache.
+fn local_sync_roots(config: &ClusterConfig) -> Result<Vec<SyncRoot>, String> {
+    let mut paths = vec![];
+    if let Some(project_folder_path) = &config.project_folder_path {
+        paths.push(project_folder_path.clone());
+    }
+    if let Some(config_path) = config.services.server.as_ref().and_then(|server| server.config_path()) {
+        paths.push(config_path);
+    }
+
+    let mut sync_roots = vec![];
+    for path in paths {
+        let path = fs::canonicalize(&path).map_err(|e| format!("Failed to read {} : {}", path, e))?;
+        if path.is_dir() {
+            let mut files = BTreeMap::new();
+            collect_files(&path, &path, &mut files)?;
+            sync_roots.push(SyncRoot {
+                root: path,
+                files,
+                is_folder: true,
+            });
+        } else {
+            let root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let mut files = BTreeMap::new();
+            files.insert(name, local_file(&path)?);
+            sync_roots.push(SyncRoot {
+                root,
+                files,
+                is_folder: false,
+            });
+        }
+    }
+    Ok(sync_roots)
+}
+
+fn collect_files(root: &Path, folder: &Path, files: &mut BTreeMap<String, LocalFile>) -> Result<(), String> {
+    let entries = fs::read_dir(folder).map_err(|e| format!("Failed to read {} : {}", folder.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {} : {}", folder.display(), e))?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .map_err(|e| format!("Failed to read {} : {}", path.display(), e))?;
+
+        // L'historique git n'a rien à faire sur les nodes, les liens symboliques sont ignorés.
+        if file_type.is_dir() && entry.file_name() != ".git" {
+            collect_files(root, &path, files)?;
+        } else if file_type.is_file() {
+            let relative_path = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string();
+            files.insert(relative_path, local_file(&path)?);
+        }
+    }
+    Ok(())
+}
+
+fn local_file(path: &Path) -> Result<LocalFile, String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("Failed to read {} : {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| format!("Failed to read {} : {}", path.display(), e))?;
+    let mode = file
+        .metadata()
+        .map(|metadata| metadata.permissions().mode() & 0o777)
+        .unwrap_or(0o644);
+
+    Ok(LocalFile {
+        path: path.to_path_buf(),
+        hash: format!("{:x}", hasher.finalize()),
+        mode,
+    })
+}
+
+// Compare les empreintes des fichiers de la node à celles des fichiers locaux, puis copie
+// ceux qui diffèrent et supprime ceux qui n'existent plus. Renvoie le nombre de fichiers
+// copiés, supprimés et inchangés.
+fn sync_root_to_node(
+    session: &dyn RemoteExecutor,
+    sync_root: &SyncRoot,
+    username: &str,
+) -> Result<(usize, usize, usize), String> {
+    let root = shell_quote(&sync_root.root.to_string_lossy());
+    if sync_root.is_folder {
+        prepare_folder(session, sync_root, username)?;
+    }
+
+    let remote_hashes = remote_hashes(session, sync_root)?;
+
+    let changed: Vec<(&String, &LocalFile)> = sync_root
+        .files
+        .iter()
+        .filter(|(relative_path, local_file)| remote_hashes.get(*relative_path) != Some(&local_file.hash))
+        .collect();
+
+    if !sync_root.is_folder {
+        for (relative_path, local_file) in &changed {
+            install_file(session, &sync_root.root.join(relative_path), local_file)?;
+        }
+        return Ok((changed.len(), 0, sync_root.files.len() - changed.len()));
+    }
+
+    let folders: BTreeSet<String> = changed
+        .iter()
+        .filter_map(|(relative_path, _)| {
+            let parent = Path::new(relative_path.as_str()).parent()?.to_string_lossy().to_string();
+            (!parent.is_empty()).then_some(parent)
+        })
+        .collect();
+    if !folders.is_empty() {
+        let folders: Vec<String> = folders.iter().map(|folder| shell_quote(folder)).collect();
+        let output = session.exec(&format!("cd {} && mkdir -p {}", root, folders.join(" ")))?;
+        if !output.success() {
+            return Err(format!("Failed to create folders : {}", output.stderr.trim()));
+        }
+    }
+
+    for (relative_path, local_file) in &changed {
+        let target = shell_quote(&sync_root.root.join(relative_path).to_string_lossy());
+        let output = session.exec_with_file(
+            &format!("cat > {target} && chmod {:o} {target}", local_file.mode),
+            &local_file.path,
+        )?;
+        if !output.success() {
+            return Err(format!("Failed to copy {} : {}", relative_path, output.stderr.trim()));
+        }
+    }
+
+    let removed: Vec<String> = remote_hashes
+        .keys()
+        .filter(|relative_path| !sync_root.files.contains_key(*relative_path))
+        .map(|relative_path| shell_quote(relative_path))
+        .collect();
+    if !removed.is_empty() {
+        let output = session.exec(&format!("cd {} && rm -f {}", root, removed.join(" ")))?;
+        if !output.success() {
+            return Err(format!("Failed to remove deleted files : {}", output.stderr.trim()));
+        }
+    }
+
+    Ok((changed.len(), removed.len(), sync_root.files.len() - changed.len()))
+}
+
+// Le chemin est identique à celui du manager, il peut être hors du dossier de l'utilisateur :
+// un dossier absent est créé pour lui, un dossier existant doit déjà lui être accessible en
+// écriture. Rien de ce qui existe sur la node ne change de propriétaire.
+fn prepare_folder(session: &dyn RemoteExecutor, sync_root: &SyncRoot, username: &str) -> Result<(), String> {
+    let root = shell_quote(&sync_root.root.to_string_lossy());
+
+    let output = session.exec_sudo(&format!(
+        "if [ ! -e {root} ]; then mkdir -p {root} && chown {} {root}; fi",
+        shell_quote(username)
+    ))?;
+    if !output.success() {
+        return Err(format!("Failed to create {} : {}", sync_root.root.display(), output.stderr.trim()));
+    }
+
+    let output = session.exec(&format!("test -d {root} && test -w {root}"))?;
+    if !output.success() {
+        return Err(format!(
+            "{} is not a folder writable by {} on the node",
+            sync_root.root.display(),
+            username
+        ));
+    }
+    Ok(())
+}
+
+// Les fichiers de configuration du serveur sont installés en root, sans toucher au dossier
+// qui les contient : ils passent par un fichier temporaire de l'utilisateur.
+fn install_file(session: &dyn RemoteExecutor, target: &Path, local_file: &LocalFile) -> Result<(), String> {
+    let output = session.exec_with_file("temp_file=$(mktemp) && cat > \"$temp_file\" && echo \"$temp_file\"", &local_file.path)?;
+    let temp_file = output.stdout.trim();
+    if !output.success() || temp_file.is_empty() {
+        return Err(format!("Failed to copy {} : {}", target.display(), output.stderr.trim()));
+    }
+
+    let folder = target.parent().unwrap_or(Path::new("/"));
+    let output = session.exec_sudo(&format!(
+        "mkdir -p {folder} && install -m {:o} {temp_file} {target}; status=$?; rm -f {temp_file}; exit $status",
+        local_file.mode,
+        folder = shell_quote(&folder.to_string_lossy()),
+        temp_file = shell_quote(temp_file),
+        target = shell_quote(&target.to_string_lossy())
+    ))?;
+    if !output.success() {
+        return Err(format!("Failed to install {} : {}", target.display(), output.stderr.trim()));
+    }
+    Ok(())
+}
+
+// Empreintes sha256 des fichiers déjà présents sur la node, par chemin relatif.
+fn remote_hashes(session: &dyn RemoteExecutor, sync_root: &SyncRoot) -> Result<BTreeMap<String, String>, String> {
+    let root = shell_quote(&sync_root.root.to_string_lossy());
+    let command = if sync_root.is_folder {
+        format!("cd {} && find . -path ./.git -prune -o -type f -exec sha256sum {{}} +", root)
+    } else {
+        let names: Vec<String> = sync_root.files.keys().map(|name| shell_quote(name)).collect();
+        // sha256sum échoue pour un fichier absent, sa sortie reste exploitable.
+        format!("cd {} && sha256sum {} 2>/dev/null; true", root, names.join(" "))
+    };
+
+    let output = session.exec(&command)?;
+    if !output.success() {
+        return Err(format!("Failed to list {} : {}", sync_root.root.display(), output.stderr.trim()));
+    }
+
+    Ok(output
+        .stdout
+        .lines()
+        .filter_map(|line| {
+            let (hash, relative_path) = line.split_once("  ")?;
+            Some((relative_path.trim_start_matches("./").to_string(), hash.to_string()))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::runner::{RecordingRunner, Target, recording_config};
+
+    #[test]
+    fn sync_copies_changed_files_and_removes_deleted_ones() {
+        let project = std::env::temp_dir().join(format!("ClusterNoodle-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&project);
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::create_dir_all(project.join(".git")).unwrap();
+        fs::write(project.join("index.php"), "unchanged").unwrap();
+        fs::write(project.join("src/app.php"), "changed").unwrap();
+        fs::write(project.join(".git/HEAD"), "ref").unwrap();
+
+        let runner = RecordingRunner::new();
+        let unchanged_hash = format!("{:x}", Sha256::digest("unchanged"));
+        runner.respond(
+            "find .",
+            0,
+            &format!("{}  ./index.php\n{}  ./src/app.php\n{}  ./old.php\n", unchanged_hash, "0".repeat(64), "1".repeat(64)),
+            "",
+        );
+        let cluster_file = format!(
+            "version: 1\nproject_folder_path: {}\nnodes:\n  - ip: 10.0.0.2\n    username: deploy\n",
+            project.display()
+        );
+        let config = recording_config(&cluster_file, &runner);
+        let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+
+        let results = sync_project(&config, &nodes);
+        assert_eq!(results[0].result, Ok(String::from("1 file(s) copied, 1 removed, 1 unchanged")));
+
+        let root = fs::canonicalize(&project).unwrap().display().to_string();
+        assert_eq!(
+            runner.recorded_on(&Target::Node(String::from("10.0.0.2"))),
+            vec![
+                format!("if [ ! -e '{root}' ]; then mkdir -p '{root}' && chown 'deploy' '{root}'; fi"),
+                format!("test -d '{root}' && test -w '{root}'"),
+                format!("cd '{root}' && find . -path ./.git -prune -o -type f -exec sha256sum {{}} +"),
+                format!("cd '{root}' && mkdir -p 'src'"),
+                format!("cat > '{root}/src/app.php' && chmod 644 '{root}/src/app.php'"),
+                format!("cd '{root}' && rm -f 'old.php'"),
+            ]
+        );
+        fs::remove_dir_all(&project).unwrap();
+    }
+
+    #[test]
+    fn sync_refuses_folders_the_user_cannot_write() {
+        let project = std::env::temp_dir().join(format!("ClusterNoodle-sync-readonly-{}", std::process::id()));
+        fs::create_dir_all(&project).unwrap();
+
+        let runner = RecordingRunner::new();
+        runner.respond("test -w", 1, "", "");
+        let cluster_file = format!(
+            "version: 1\nproject_folder_path: {}\nnodes:\n  - ip: 10.0.0.2\n    username: deploy\n",
+            project.display()
+        );
+        let config = recording_config(&cluster_file, &runner);
+        let nodes: Vec<&NodeConfig> = config.nodes_configs.iter().collect();
+
+        let results = sync_project(&config, &nodes);
+        let error = results[0].result.clone().err().unwrap();
+        assert!(error.contains("is not a folder writable by deploy"), "{}", error);
+        assert!(runner.recorded().iter().all(|recorded| !recorded.command.contains("chown -R")));
+        fs::remove_dir_all(&project).unwrap();
+    }
+}